@@ -0,0 +1,401 @@
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, set_permissions, Permissions};
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use zeroize::Zeroizing;
+
+use crate::vault_dir::VaultDir;
+use crate::{Encrypter, SecretString};
+
+pub const SOCKET_NAME: &str = "agent.sock";
+
+/// How often the agent checks for new connections and the idle timeout.
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How long a client may take to send its request.
+pub(crate) const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Associated data and ciphertexts travel base64 and `nonce:payload` encoded,
+/// the same as in the database.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+enum Request {
+    Unlock,
+    Encrypt { plain: SecretString, aad: String },
+    Decrypt { payload: String, aad: String },
+    Lock,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+enum Response {
+    Unlocked,
+    Encrypted { payload: String },
+    Decrypted { plain: SecretString },
+    Failed { message: String },
+    Locked,
+    Denied { message: String },
+}
+
+/// A connection to a running agent, which encrypts and decrypts fields with
+/// the vault key on behalf of this process.
+pub(crate) struct Session {
+    stream: Mutex<BufReader<UnixStream>>,
+}
+
+impl Session {
+    pub(crate) fn encrypt(&self, plain: &str, aad: &[u8]) -> Result<String> {
+        let request = Request::Encrypt {
+            plain: SecretString::new(plain.to_owned()),
+            aad: STANDARD.encode(aad),
+        };
+        match self.call(&request)? {
+            Response::Encrypted { payload } => Ok(payload),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub(crate) fn decrypt(&self, payload: &str, aad: &[u8]) -> Result<String> {
+        let request = Request::Decrypt {
+            payload: payload.to_owned(),
+            aad: STANDARD.encode(aad),
+        };
+        match self.call(&request)? {
+            Response::Decrypted { plain } => Ok(plain.expose().to_owned()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn call(&self, request: &Request) -> Result<Response> {
+        let mut stream = self
+            .stream
+            .lock()
+            .map_err(|_| anyhow!("Agent connection poisoned"))?;
+        exchange(&mut stream, request)
+    }
+}
+
+/// Whether an agent of `vault` is listening on its socket.
+pub fn is_running(vault: &VaultDir) -> bool {
+    UnixStream::connect(vault.socket()).is_ok()
+}
+
+/// Opens a session with a running agent, returning an encrypter that has the
+/// agent encrypt and decrypt fields. The vault key itself never leaves the
+/// agent.
+///
+/// Returns `None` if no agent is running or its key no longer opens the
+/// keyfile, e.g. after the master was changed, so callers can fall back to
+/// prompting for the master.
+pub fn fetch(vault: &VaultDir) -> Option<Encrypter> {
+    let mut stream = connect(vault).ok()?;
+    match exchange(&mut stream, &Request::Unlock).ok()? {
+        Response::Unlocked => {
+            let session = Session {
+                stream: Mutex::new(stream),
+            };
+            Encrypter::attach(vault, session).ok()
+        }
+        _ => None,
+    }
+}
+
+/// Tells a running agent to drop the key and exit. Returns `false` if no agent
+/// was running.
+pub fn lock(vault: &VaultDir) -> Result<bool> {
+    if !is_running(vault) {
+        return Ok(false);
+    }
+    match exchange(&mut connect(vault)?, &Request::Lock)? {
+        Response::Locked => {
+            wait_closed(&vault.socket());
+            Ok(true)
+        }
+        response => Err(unexpected(response)),
+    }
+}
+
+/// Waits for a stopping agent to remove its socket at `path`. Agents only
+/// notice a stop on their next poll, until then a new one can't be started.
+pub(crate) fn wait_closed(path: &Path) {
+    let stopping = Instant::now();
+    while path.exists() && stopping.elapsed() < CLIENT_TIMEOUT {
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn connect(vault: &VaultDir) -> Result<BufReader<UnixStream>> {
+    let stream = UnixStream::connect(vault.socket())?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    Ok(BufReader::new(stream))
+}
+
+fn exchange(stream: &mut BufReader<UnixStream>, request: &Request) -> Result<Response> {
+    let line = Zeroizing::new(serde_json::to_string(request)?);
+    stream.get_mut().write_all(line.as_bytes())?;
+    stream.get_mut().write_all(b"\n")?;
+
+    let mut line = Zeroizing::new(String::new());
+    if stream.read_line(&mut line)? == 0 {
+        return Err(anyhow!("The agent closed the connection"));
+    }
+    Ok(serde_json::from_str(&line)?)
+}
+
+fn unexpected(response: Response) -> anyhow::Error {
+    match response {
+        Response::Failed { message } | Response::Denied { message } => anyhow!("{message}"),
+        _ => anyhow!("Unexpected answer from agent"),
+    }
+}
+
+/// An agent bound to its socket that doesn't answer anyone yet.
+///
+/// Clients only get through once [`Agent::serve`] runs. The library never
+/// forks, the CLI serves from a detached child of its own, an embedding app
+/// can serve from a thread.
+pub struct Agent {
+    listener: UnixListener,
+    socket: PathBuf,
+    serve: Box<Serve>,
+}
+
+/// Answers the clients of an agent on its listener.
+type Serve = dyn FnOnce(&UnixListener) -> Result<()> + Send;
+
+impl Agent {
+    /// Binds `socket` for clients that `serve` answers.
+    pub(crate) fn bind(
+        socket: PathBuf,
+        serve: impl FnOnce(&UnixListener) -> Result<()> + Send + 'static,
+    ) -> Result<Self> {
+        Ok(Self {
+            listener: bind(&socket)?,
+            socket,
+            serve: Box::new(serve),
+        })
+    }
+
+    /// Answers clients until the agent is locked or idle for its timeout,
+    /// then removes the socket.
+    pub fn serve(self) -> Result<()> {
+        let result = (self.serve)(&self.listener);
+        let _ = fs::remove_file(&self.socket);
+        result
+    }
+
+    pub fn socket(&self) -> &Path {
+        &self.socket
+    }
+}
+
+/// Binds the agent socket of the vault `encrypter` unlocks, to encrypt and
+/// decrypt with its key until it is locked or idle for `timeout`.
+pub fn bind_agent(encrypter: Encrypter, timeout: Duration) -> Result<Agent> {
+    Agent::bind(encrypter.vault().socket(), move |listener| {
+        // Memory locks are not inherited by fork, a fresh copy locks the key
+        // pages again in the process that serves.
+        let agent = encrypter.clone();
+        drop(encrypter);
+        serve(listener, agent, timeout)
+    })
+}
+
+pub(crate) fn bind(path: &Path) -> Result<UnixListener> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(anyhow!(
+                "An agent is already running -> Use 'candado lock' to stop it."
+            ));
+        }
+        // Left behind by an agent that did not shut down cleanly
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    set_permissions(path, Permissions::from_mode(0o600))?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+pub(crate) fn serve(
+    listener: &UnixListener,
+    encrypter: Encrypter,
+    timeout: Duration,
+) -> Result<()> {
+    serve_clients(listener, timeout, move |stream, activity| {
+        handle(stream, &encrypter, activity)
+    })
+}
+
+/// What the client threads of an agent share: when it was last used and
+/// whether a client asked it to stop.
+pub(crate) struct Activity {
+    last_used: Mutex<Instant>,
+    stopped: AtomicBool,
+}
+
+impl Activity {
+    pub(crate) fn new() -> Self {
+        Self {
+            last_used: Mutex::new(Instant::now()),
+            stopped: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn touch(&self) {
+        if let Ok(mut last) = self.last_used.lock() {
+            *last = Instant::now();
+        }
+    }
+
+    pub(crate) fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// How long ago the agent answered its last request.
+    fn idle(&self) -> Duration {
+        self.last_used
+            .lock()
+            .map_or(Duration::MAX, |last| last.elapsed())
+    }
+}
+
+/// Accepts clients on `listener` until one stops the agent or it is idle for
+/// `timeout`. Clients may keep their connection open, so each one is passed
+/// to `handle` on its own thread.
+pub(crate) fn serve_clients<F>(listener: &UnixListener, timeout: Duration, handle: F) -> Result<()>
+where
+    F: Fn(UnixStream, &Activity) -> Result<()> + Send + Sync + 'static,
+{
+    let handle = Arc::new(handle);
+    let activity = Arc::new(Activity::new());
+    while !activity.is_stopped() && activity.idle() < timeout {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let (handle, activity) = (handle.clone(), activity.clone());
+                thread::spawn(move || {
+                    // A misbehaving client must not take the agent down
+                    let _ = handle(stream, &activity);
+                });
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+fn handle(mut stream: UnixStream, encrypter: &Encrypter, activity: &Activity) -> Result<()> {
+    stream.set_nonblocking(false)?;
+
+    // The socket is only reachable through the vault folder, checking the
+    // peer guards against the folder permissions being loosened.
+    // SAFETY: getuid has no preconditions.
+    let uid = unsafe { libc::getuid() };
+    if peer_uid(&stream)? != uid {
+        let denied = Response::Denied {
+            message: "Permission denied".to_owned(),
+        };
+        stream.write_all(format!("{}\n", serde_json::to_string(&denied)?).as_bytes())?;
+        return Err(anyhow!("Rejected client of another user"));
+    }
+
+    // The encrypter of this session, reloaded from the keyfile on unlock
+    let mut session = None;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    loop {
+        let mut line = Zeroizing::new(String::new());
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        activity.touch();
+
+        let request: Request = serde_json::from_str(&line)?;
+        let lock = matches!(request, Request::Lock);
+        let response = match request {
+            Request::Unlock => match encrypter.reload() {
+                Ok(current) => {
+                    session = Some(current);
+                    Response::Unlocked
+                }
+                Err(_) => Response::Denied {
+                    message: "The vault key changed since the agent started".to_owned(),
+                },
+            },
+            Request::Lock => {
+                activity.stop();
+                Response::Locked
+            }
+            request => match &session {
+                Some(session) => apply(session, request).unwrap_or_else(|e| Response::Failed {
+                    message: format!("{e:#}"),
+                }),
+                None => Response::Denied {
+                    message: "No session, unlock first".to_owned(),
+                },
+            },
+        };
+        let answer = Zeroizing::new(serde_json::to_string(&response)?);
+        stream.write_all(answer.as_bytes())?;
+        stream.write_all(b"\n")?;
+        if lock {
+            return Ok(());
+        }
+    }
+}
+
+/// Encrypts or decrypts a field for a client with the key of `encrypter`.
+fn apply(encrypter: &Encrypter, request: Request) -> Result<Response> {
+    match request {
+        Request::Encrypt { plain, aad } => {
+            let payload = encrypter.encrypt_with(plain.expose(), &STANDARD.decode(aad)?)?;
+            Ok(Response::Encrypted {
+                payload: String::from_utf8(payload)?,
+            })
+        }
+        Request::Decrypt { payload, aad } => {
+            let plain = encrypter.decrypt_with(payload.as_bytes(), &STANDARD.decode(aad)?)?;
+            Ok(Response::Decrypted {
+                plain: SecretString::new(plain),
+            })
+        }
+        _ => Err(anyhow!("Unexpected request")),
+    }
+}
+
+pub(crate) fn peer_uid(stream: &UnixStream) -> Result<u32> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: `cred` and `len` are valid for writes of the requested size.
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut cred as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(cred.uid)
+}
@@ -1,23 +1,115 @@
 use anyhow::anyhow;
 use candadolib::{
-    add, export, find, import, init, key, ls, passphrase, password, read, rm, token,
+    check_keyfile, combine, create_key_file, harden_process, init, kdf_bench, list_vaults, lock,
+    passwd, recover, render, start_agent, start_ssh_agent,
     tui::{self, App, TableApp},
-    unlock, update, Encrypter, ABOUT, VERSION,
+    Agent, CandadoError, CipherSuite, Credentials, Entry, EnvVar, Format, GitCredential, KdfCost,
+    MasterSource, RecoveryKey, SecretString, SshKeyType, Vault, VaultDir, ABOUT, VERSION,
 };
-use clap::{Parser, Subcommand};
-use std::{fs, io::Write, path::PathBuf};
+use clap::{Args, Parser, Subcommand};
+use std::{
+    fs::File,
+    io::{IsTerminal, Write},
+    os::{fd::AsRawFd, unix::process::CommandExt},
+    path::PathBuf,
+    process::ExitCode,
+    time::Duration,
+};
+use zeroize::Zeroizing;
 
 #[derive(Parser)]
-#[command(version = VERSION, about = ABOUT, long_about = None)]
+#[command(version = VERSION, about = ABOUT, long_about = None, after_help = EXIT_CODES)]
 pub struct CandadoCLI {
     #[command(subcommand)]
     apps: Apps,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "NAME|PATH",
+        help = "vault to use, a name in the candado home or a path containing '/'"
+    )]
+    vault: Option<String>,
+
+    #[command(flatten)]
+    output: OutputArgs,
 }
 
 #[derive(Subcommand)]
 enum Apps {
     Gen(Generators),
     Vault(Manager),
+    #[command(about = "Keep the vault unlocked in the background until idle")]
+    Agent {
+        #[arg(
+            short = 't',
+            long,
+            default_value_t = 900,
+            help = "seconds of inactivity before the agent locks"
+        )]
+        timeout: u64,
+        #[command(flatten)]
+        unlock: UnlockArgs,
+    },
+    #[command(about = "Serve the SSH keys of the vault to ssh until idle")]
+    SshAgent {
+        #[arg(
+            short = 't',
+            long,
+            default_value_t = 900,
+            help = "seconds of inactivity before the agent locks"
+        )]
+        timeout: u64,
+        #[command(flatten)]
+        unlock: UnlockArgs,
+    },
+    #[command(about = "Stop the agents and drop the keys right away")]
+    Lock,
+    #[command(about = "Run a command with secrets from the vault in its environment")]
+    Run {
+        #[arg(
+            short = 'e',
+            long,
+            value_name = "VAR=ID:FIELD",
+            help = "set VAR to a field of an entry, e.g. API_KEY=<id>:custom.token"
+        )]
+        env: Vec<EnvVar>,
+        #[arg(last = true, required = true, value_name = "COMMAND")]
+        command: Vec<String>,
+        #[command(flatten)]
+        unlock: UnlockArgs,
+    },
+    #[command(about = "Fill {{ candado://<id>/<field> }} references in a template")]
+    Inject {
+        #[arg(short = 'i', long, help = "template with secret references")]
+        input: PathBuf,
+        #[arg(short = 'o', long, help = "file to write, readable by the owner alone")]
+        output: PathBuf,
+        #[command(flatten)]
+        unlock: UnlockArgs,
+    },
+    #[command(
+        about = "Git credential helper, set with: git config credential.helper 'candado git-credential'"
+    )]
+    GitCredential {
+        #[arg(help = "get, store or erase, sent by git")]
+        operation: String,
+        #[command(flatten)]
+        unlock: UnlockArgs,
+    },
+}
+
+impl Apps {
+    /// Whether the command writes entries, the only ones taking --format.
+    fn shows_entries(&self) -> bool {
+        matches!(
+            self,
+            Apps::Vault(Manager {
+                command: Command::Ls | Command::Find { .. } | Command::Inspect { .. },
+                ..
+            })
+        )
+    }
 }
 
 #[derive(Parser)]
@@ -32,6 +124,68 @@ struct Generators {
 struct Manager {
     #[command(subcommand)]
     pub command: Command,
+
+    #[command(flatten)]
+    pub unlock: UnlockArgs,
+}
+
+/// How the vault is unlocked. The master itself is never taken as an
+/// argument, only the place to read it from.
+#[derive(Args)]
+struct UnlockArgs {
+    #[arg(
+        long,
+        global = true,
+        help = "key file needed next to the master, created by init if missing"
+    )]
+    pub keyfile: Option<PathBuf>,
+    #[arg(
+        long,
+        global = true,
+        conflicts_with = "password_file",
+        help = "read the master from stdin, one line per prompt"
+    )]
+    pub password_stdin: bool,
+    #[arg(
+        long,
+        global = true,
+        help = "read the master from a file, one line per prompt"
+    )]
+    pub password_file: Option<PathBuf>,
+}
+
+impl UnlockArgs {
+    fn credentials(self, vault: VaultDir) -> Result<Credentials, anyhow::Error> {
+        let source = MasterSource::select(self.password_stdin, self.password_file)?;
+        Ok(Credentials::new(vault, source, self.keyfile))
+    }
+}
+
+/// How ls, find and inspect write entries. Global like --vault, the other
+/// commands reject them instead of ignoring them.
+#[derive(Args)]
+struct OutputArgs {
+    #[arg(
+        long,
+        global = true,
+        help = "write entries as json, tsv or plain instead of opening the TUI, plain if stdout is not a terminal"
+    )]
+    format: Option<Format>,
+    #[arg(
+        long,
+        global = true,
+        value_delimiter = ',',
+        help = "fields to write with --format, e.g. service,email"
+    )]
+    fields: Vec<String>,
+    #[arg(long, global = true, help = "show passwords in --format output")]
+    reveal: bool,
+}
+
+impl OutputArgs {
+    fn is_set(&self) -> bool {
+        self.format.is_some() || !self.fields.is_empty() || self.reveal
+    }
 }
 
 #[derive(Subcommand)]
@@ -58,12 +212,91 @@ enum Generator {
         #[arg(short = 'c', long, help = "use custom wordlist")]
         wordlist: Option<PathBuf>,
     },
+    #[command(about = "Generate an OpenSSH key pair")]
+    SshKey {
+        #[arg(short = 't', long = "type", default_value_t = SshKeyType::default(), help = "ed25519 or rsa")]
+        key_type: SshKeyType,
+        #[arg(
+            short = 'C',
+            long,
+            help = "comment stored in the key, the service name if saved"
+        )]
+        comment: Option<String>,
+        #[arg(
+            short = 's',
+            long,
+            value_name = "SERVICE",
+            help = "add the private key to the vault instead of printing it"
+        )]
+        save: Option<String>,
+        #[command(flatten)]
+        unlock: UnlockArgs,
+    },
 }
 
 #[derive(Subcommand)]
 enum Command {
     #[command(about = "init vault")]
-    Init,
+    Init {
+        #[arg(short = 'm', long, default_value_t = KdfCost::default().memory, help = "argon2 memory cost in KiB")]
+        memory: u32,
+        #[arg(short = 'i', long, default_value_t = KdfCost::default().iterations, help = "argon2 iterations")]
+        iterations: u32,
+        #[arg(short = 'p', long, default_value_t = KdfCost::default().parallelism, help = "argon2 parallelism")]
+        parallelism: u32,
+        #[arg(short = 'c', long, default_value_t = CipherSuite::default(), help = "aes-256-gcm or xchacha20-poly1305")]
+        cipher: CipherSuite,
+        #[arg(long, help = "don't generate a recovery key")]
+        no_recovery_key: bool,
+    },
+
+    #[command(about = "List the vaults in the candado home")]
+    ListVaults,
+
+    #[command(about = "Calibrate argon2 cost to a target unlock time")]
+    KdfBench {
+        #[arg(
+            short = 't',
+            long,
+            default_value_t = 1000,
+            help = "target unlock time in milliseconds"
+        )]
+        target: u64,
+        #[arg(short = 'm', long, default_value_t = 64 * 1024, help = "argon2 memory cost in KiB")]
+        memory: u32,
+        #[arg(short = 'p', long, default_value_t = KdfCost::default().parallelism, help = "argon2 parallelism")]
+        parallelism: u32,
+    },
+
+    #[command(about = "Change master")]
+    Passwd,
+
+    #[command(about = "Set a new master using the recovery key")]
+    Recover,
+
+    #[command(about = "Generate a new recovery key, replacing the current one")]
+    RecoveryKey,
+
+    #[command(about = "Split a recovery secret into shares, any threshold of them restore access")]
+    Split {
+        #[arg(short = 'n', long, help = "number of shares to hand out")]
+        shares: u8,
+        #[arg(short = 'k', long, help = "number of shares needed to restore access")]
+        threshold: u8,
+    },
+
+    #[command(about = "Set a new master by combining recovery shares")]
+    Combine,
+
+    #[command(about = "Rotate the data key and re-encrypt all entries")]
+    RotateKey {
+        #[arg(
+            short = 'c',
+            long,
+            help = "switch to another cipher: aes-256-gcm or xchacha20-poly1305"
+        )]
+        cipher: Option<CipherSuite>,
+    },
 
     #[command(about = "List entries")]
     Ls,
@@ -74,17 +307,35 @@ enum Command {
     #[command(about = "Read entry")]
     Inspect { id: String },
 
+    #[command(about = "Print one field of an entry, for scripts")]
+    Get {
+        #[arg(help = "entry id, service or part of the service or username of a single entry")]
+        query: String,
+        #[arg(
+            short = 'f',
+            long,
+            default_value = "password",
+            help = "password, username, email, url or the name of a custom field"
+        )]
+        field: String,
+    },
+
+    #[command(about = "Add an unencrypted OpenSSH private key for candado ssh-agent")]
+    AddSshKey { service: String, file: PathBuf },
+
     #[command(about = "Add an entry")]
     Add {
         service: String,
         email: String,
 
         #[arg(short = 'p', long)]
-        password: Option<String>,
+        password: Option<SecretString>,
         #[arg(short = 'n', long)]
         username: Option<String>,
         #[arg(short = 'u', long)]
         url: Option<String>,
+        #[arg(short = 'c', long, value_name = "NAME=VALUE", value_parser = parse_custom, help = "custom field, e.g. token=...")]
+        custom: Vec<(String, SecretString)>,
     },
 
     #[command(about = "Update entry")]
@@ -96,13 +347,18 @@ enum Command {
         #[arg(short = 'e', long)]
         email: Option<String>,
         #[arg(short = 'p', long)]
-        password: Option<String>,
+        password: Option<SecretString>,
         #[arg(short = 'n', long)]
         username: Option<String>,
         #[arg(short = 'u', long)]
         url: Option<String>,
+        #[arg(short = 'c', long, value_name = "NAME=VALUE", value_parser = parse_custom, help = "set a custom field, an empty value removes it")]
+        custom: Vec<(String, SecretString)>,
     },
 
+    #[command(about = "Check the whole vault for damaged or duplicate entries")]
+    Check,
+
     #[command(about = "Find entries")]
     Find { query: String },
 
@@ -116,137 +372,565 @@ enum Command {
 impl CandadoCLI {
     pub fn run() -> Result<(), anyhow::Error> {
         let cli = CandadoCLI::parse();
+        if cli.output.is_set() && !cli.apps.shows_entries() {
+            return Err(CandadoError::InvalidInput(
+                "--format, --fields and --reveal only apply to ls, find and inspect -> Remove them."
+                    .to_owned(),
+            )
+            .into());
+        }
         match cli.apps {
             Apps::Gen(gen) => match gen.generator {
                 Generator::Password { length } => {
-                    let pass = password(length);
+                    let pass = Vault::password(length);
                     println!("{pass}");
                     Ok(())
                 }
                 Generator::Token { length } => {
-                    let token = token(length);
+                    let token = Vault::token(length);
                     println!("{token}");
                     Ok(())
                 }
                 Generator::Key { length } => {
-                    let key = key(length);
+                    let key = Vault::key(length);
                     println!("{key}");
                     Ok(())
                 }
                 Generator::Passphrase { length, wordlist } => {
-                    let phrase = passphrase(length, &wordlist);
+                    let phrase = Vault::passphrase(length, &wordlist);
                     println!("{phrase}");
                     Ok(())
                 }
+                Generator::SshKey {
+                    key_type,
+                    comment,
+                    save,
+                    unlock,
+                } => {
+                    let comment = comment.or_else(|| save.clone()).unwrap_or_default();
+                    let (private_key, public_key) = Vault::ssh_key(key_type, &comment)?;
+                    match save {
+                        Some(service) => {
+                            let entry = Entry::ssh_key(service, &private_key)?;
+                            let vault = VaultDir::resolve(cli.vault.as_deref())?;
+                            let vault = Vault::unlock(&mut unlock.credentials(vault)?)?;
+                            vault.add(entry)?;
+                            println!("{public_key}");
+                        }
+                        None => {
+                            print!("{}", *private_key);
+                            println!("{public_key}");
+                        }
+                    }
+                    Ok(())
+                }
             },
-            Apps::Vault(manager) => match manager.command {
-                Command::Init => {
-                    println!("Initializing new Vault!");
-
-                    if Encrypter::load_keyfile_path().is_ok() {
-                        println!("WARNING there is already a existing Vault!");
-                        println!("continue to permantly DELETE the exsting vault.");
-                        print!("Are you sure to continue? [y/n]: ");
-                        std::io::stdout().flush().unwrap();
-
-                        let mut buffer = String::new();
-                        std::io::stdin().read_line(&mut buffer)?;
-
-                        match buffer.trim() {
-                            "y" | "Y" | "yes" | "YES" => {
-                                println!("Deleting vault...");
-                                let path = format!("{}/.candado", std::env::var("HOME")?);
-                                fs::remove_dir_all(path)?;
+            Apps::Agent { timeout, unlock } => {
+                let vault = VaultDir::resolve(cli.vault.as_deref())?;
+                let mut credentials = unlock.credentials(vault)?;
+                let agent = start_agent(&mut credentials, Duration::from_secs(timeout))?;
+                let pid = spawn_detached(agent)?;
+                println!(
+                    "Agent started (pid {pid}), it locks after {timeout} seconds of inactivity."
+                );
+                Ok(())
+            }
+            Apps::SshAgent { timeout, unlock } => {
+                let vault = VaultDir::resolve(cli.vault.as_deref())?;
+                let mut credentials = unlock.credentials(vault)?;
+                let agent = start_ssh_agent(&mut credentials, Duration::from_secs(timeout))?;
+                let pid = spawn_detached(agent)?;
+                // Shell commands like ssh-agent prints them, for eval "$(candado ssh-agent)"
+                let socket = credentials.vault().ssh_socket().display().to_string();
+                println!(
+                    "SSH_AUTH_SOCK='{}'; export SSH_AUTH_SOCK;",
+                    socket.replace('\'', "'\\''")
+                );
+                println!(
+                    "echo SSH agent pid {pid}, it locks after {timeout} seconds of inactivity.;"
+                );
+                Ok(())
+            }
+            Apps::Lock => {
+                let vault = VaultDir::resolve(cli.vault.as_deref())?;
+                match lock(&vault)? {
+                    true => println!("Vault locked: OK"),
+                    false => println!("No agent running."),
+                }
+                Ok(())
+            }
+            Apps::Run {
+                env,
+                command,
+                unlock,
+            } => {
+                let vault = VaultDir::resolve(cli.vault.as_deref())?;
+                let vault = Vault::unlock(&mut unlock.credentials(vault)?)?;
+                let mut child = vault.command(&env, &command)?;
+                drop(vault);
+                // Only returns if the command couldn't be started
+                let err = child.exec();
+                Err(anyhow::Error::new(err).context(format!("Can't run '{}'", command[0])))
+            }
+            Apps::Inject {
+                input,
+                output,
+                unlock,
+            } => {
+                let vault = VaultDir::resolve(cli.vault.as_deref())?;
+                let vault = Vault::unlock(&mut unlock.credentials(vault)?)?;
+                vault.inject(&input, &output)?;
+                println!("Injected: OK");
+                Ok(())
+            }
+            Apps::GitCredential { operation, unlock } => {
+                // Helpers are asked to ignore operations they don't know
+                if !matches!(operation.as_str(), "get" | "store" | "erase") {
+                    return Ok(());
+                }
+                let credential = GitCredential::read(std::io::stdin().lock())?;
+                let vault = VaultDir::resolve(cli.vault.as_deref())?;
+                let vault = Vault::unlock(&mut unlock.credentials(vault)?)?;
+                match operation.as_str() {
+                    "get" => match credential.find(&vault)? {
+                        Some(entry) => GitCredential::write(&entry, std::io::stdout()),
+                        None => Ok(()),
+                    },
+                    "store" => credential.store(&vault),
+                    _ => credential.erase(&vault),
+                }
+            }
+            Apps::Vault(manager) => {
+                let vault = VaultDir::resolve(cli.vault.as_deref())?;
+                let mut credentials = manager.unlock.credentials(vault)?;
+                match manager.command {
+                    Command::Init {
+                        memory,
+                        iterations,
+                        parallelism,
+                        cipher,
+                        no_recovery_key,
+                    } => {
+                        println!("Initializing new Vault!");
+
+                        let replace = credentials.vault().exists();
+                        if replace {
+                            if !credentials.is_interactive() {
+                                return Err(CandadoError::InvalidInput("There is already a existing Vault -> Delete it before initializing a new one without a terminal.".to_owned()).into());
                             }
-                            _ => {
+                            println!("WARNING there is already a existing Vault!");
+                            println!("continue to permantly DELETE the exsting vault.");
+                            print!("Are you sure to continue? [y/n]: ");
+                            std::io::stdout().flush().unwrap();
+
+                            let mut buffer = String::new();
+                            std::io::stdin().read_line(&mut buffer)?;
+
+                            if !matches!(buffer.trim(), "y" | "Y" | "yes" | "YES") {
                                 return Err(anyhow!("Aborted."));
                             }
                         }
-                    }
 
-                    println!("WARNING");
-                    println!("Make sure to use a strong password! Do not only rely on encryption.");
-                    println!("Make sure to remember your passwort or save it in a safe location, you CAN'T recover access if you loose your password!");
+                        println!("WARNING");
+                        println!(
+                            "Make sure to use a strong password! Do not only rely on encryption."
+                        );
+                        if no_recovery_key {
+                            println!("Make sure to remember your passwort or save it in a safe location, you CAN'T recover access if you loose your password!");
+                        } else {
+                            println!("Make sure to remember your passwort or save it in a safe location, the recovery key is the only way back in if you loose your password!");
+                        }
+
+                        let created = match credentials.key_file() {
+                            Some(path) if !path.exists() => {
+                                create_key_file(path)?;
+                                Some(path.to_owned())
+                            }
+                            _ => None,
+                        };
 
-                    match init() {
-                        Ok(()) => println!("Vault Created!"),
-                        Err(e) => println!("{e}"),
+                        let cost = KdfCost {
+                            memory,
+                            iterations,
+                            parallelism,
+                        };
+                        let recovery_key =
+                            match init(&mut credentials, cost, cipher, !no_recovery_key, replace) {
+                                Ok(recovery_key) => recovery_key,
+                                Err(e) => {
+                                    // A key file made for this vault is useless without it
+                                    if let Some(path) = &created {
+                                        let _ = std::fs::remove_file(path);
+                                    }
+                                    return Err(e);
+                                }
+                            };
+                        if let Some(path) = &created {
+                            println!(
+                                "Key file created at {}, keep it apart from the vault.",
+                                path.display()
+                            );
+                        }
+                        println!("Vault Created!");
+                        if let Some(recovery_key) = recovery_key {
+                            print_recovery_key(&recovery_key);
+                        }
+                        Ok(())
                     }
-                    Ok(())
-                }
-                Command::Ls => {
-                    let encrypter = unlock()?;
-                    let entries = ls(encrypter)?;
-                    tui::init(App::Table(TableApp::new(entries)?))
-                }
-                Command::Find { query } => {
-                    let encrypter = unlock()?;
-                    let entries = find(encrypter, &query)?;
-                    tui::init(App::Table(TableApp::new(entries)?))
-                }
-                Command::Inspect { id } => {
-                    let encrypter = unlock()?;
-                    let entry = read(encrypter, &id)?;
-                    tui::init(App::Table(TableApp::new(vec![entry])?))
-                }
-                Command::Add {
-                    service,
-                    email,
-                    password,
-                    username,
-                    url,
-                } => {
-                    let encrypter = unlock()?;
-                    match add(encrypter, service, email, password, username, url) {
-                        Ok(()) => println!("Entry added: OK"),
-                        Err(e) => println!("{e}"),
+                    Command::ListVaults => {
+                        let vaults = list_vaults()?;
+                        if vaults.is_empty() {
+                            println!("No vaults in {} -> Use 'candado vault init' to initialize a new vault.", VaultDir::home()?.display());
+                        }
+                        for (name, vault) in vaults {
+                            let current = if &vault == credentials.vault() {
+                                "*"
+                            } else {
+                                " "
+                            };
+                            println!("{current} {name:<16} {vault}");
+                        }
+                        Ok(())
                     }
-                    Ok(())
-                }
-                Command::Update {
-                    id,
-                    service,
-                    email,
-                    password,
-                    username,
-                    url,
-                } => {
-                    let encrypter = unlock()?;
-                    match update(encrypter, &id, service, email, password, username, url) {
-                        Ok(()) => println!("Entry updated: OK"),
-                        Err(e) => println!("{e}"),
+                    Command::KdfBench {
+                        target,
+                        memory,
+                        parallelism,
+                    } => {
+                        println!("Calibrating argon2, this may take a few seconds...");
+                        let (cost, elapsed) =
+                            kdf_bench(Duration::from_millis(target), memory, parallelism)?;
+                        println!(
+                            "Unlock takes {} ms with: --memory {} --iterations {} --parallelism {}",
+                            elapsed.as_millis(),
+                            cost.memory,
+                            cost.iterations,
+                            cost.parallelism
+                        );
+                        Ok(())
                     }
-                    Ok(())
-                }
-                Command::Rm { id } => {
-                    let encrypter = unlock()?;
-                    match rm(encrypter, &id) {
-                        Ok(()) => println!("Entry deleted: OK"),
-                        Err(e) => println!("{e}"),
+                    Command::Passwd => {
+                        passwd(&mut credentials)?;
+                        println!("Master changed: OK");
+                        Ok(())
                     }
-                    Ok(())
-                }
-                Command::Import { file } => {
-                    let encrypter = unlock()?;
-                    match import(encrypter, file) {
-                        Ok(()) => println!("Import: OK"),
-                        Err(e) => println!("{e}"),
+                    Command::Recover => {
+                        recover(&mut credentials)?;
+                        println!("Master changed: OK");
+                        Ok(())
                     }
-                    Ok(())
-                }
-                Command::Export { file } => {
-                    let encrypter = unlock()?;
-                    match export(encrypter, file) {
-                        Ok(()) => println!("Export: OK"),
-                        Err(e) => println!("{e}"),
+                    Command::RecoveryKey => {
+                        let mut vault = Vault::unlock_master(&mut credentials)?;
+                        let recovery_key = vault.recovery_key()?;
+                        println!("Recovery key replaced: OK");
+                        print_recovery_key(&recovery_key);
+                        Ok(())
+                    }
+                    Command::Split { shares, threshold } => {
+                        let mut vault = Vault::unlock_master(&mut credentials)?;
+                        let phrases = vault.split(shares, threshold)?;
+                        println!("Recovery shares replaced: OK");
+                        println!("Hand out each share to a different person, any {threshold} of them restore access with 'candado vault combine'.");
+                        for (i, phrase) in phrases.iter().enumerate() {
+                            println!("Share {} of {shares}:", i + 1);
+                            print_words(phrase);
+                        }
+                        Ok(())
+                    }
+                    Command::Combine => {
+                        combine(&mut credentials)?;
+                        println!("Master changed: OK");
+                        Ok(())
+                    }
+                    Command::RotateKey { cipher } => {
+                        let mut vault = Vault::unlock_master(&mut credentials)?;
+                        let had_shares = vault.encrypter().has_shares();
+                        let recovery_key = vault.rotate_key(cipher)?;
+                        println!("Key rotated: OK");
+                        if had_shares {
+                            println!("The recovery shares no longer work -> Use 'candado vault split' to hand out new ones.");
+                        }
+                        if let Some(recovery_key) = recovery_key {
+                            println!("The old recovery key no longer works.");
+                            print_recovery_key(&recovery_key);
+                        }
+                        Ok(())
+                    }
+                    Command::Ls => {
+                        let vault = Vault::unlock(&mut credentials)?;
+                        let entries = vault.list()?;
+                        show(entries, cli.output)
+                    }
+                    Command::Check => {
+                        // The keyfile is checked up front, its problems may be
+                        // why unlocking fails
+                        let issues = check_keyfile(credentials.vault());
+                        let vault = match Vault::unlock(&mut credentials) {
+                            Ok(vault) => vault,
+                            Err(e) => {
+                                for issue in &issues {
+                                    println!("{issue}");
+                                }
+                                return Err(e);
+                            }
+                        };
+                        let report = vault.check()?;
+                        for issue in &report.issues {
+                            println!("{issue}");
+                        }
+                        for failure in &report.failures {
+                            println!(
+                                "row {} (entry '{}'): {}",
+                                failure.row,
+                                failure.entry_id.as_deref().unwrap_or("?"),
+                                failure.error
+                            );
+                        }
+                        for id in &report.duplicates {
+                            println!("entry id '{id}' is used by more than one entry");
+                        }
+                        println!(
+                            "Checked {} entries: {} damaged, {} duplicate ids, {} other issues",
+                            report.entries,
+                            report.failures.len(),
+                            report.duplicates.len(),
+                            report.issues.len()
+                        );
+                        if !report.is_ok() {
+                            return Err(
+                                CandadoError::Corrupt("Vault check failed.".to_owned()).into()
+                            );
+                        }
+                        Ok(())
+                    }
+                    Command::Find { query } => {
+                        let vault = Vault::unlock(&mut credentials)?;
+                        let entries = vault.find(&query)?;
+                        show(entries, cli.output)
+                    }
+                    Command::Inspect { id } => {
+                        let vault = Vault::unlock(&mut credentials)?;
+                        let entry = vault.read(&id)?;
+                        show(vec![entry], cli.output)
+                    }
+                    Command::Get { query, field } => {
+                        let vault = Vault::unlock(&mut credentials)?;
+                        let value = vault.get(&query, &field)?;
+                        let mut stdout = std::io::stdout();
+                        stdout.write_all(value.expose().as_bytes())?;
+                        // Only a terminal gets a newline, $(...) and pipes get the bare value
+                        if stdout.is_terminal() {
+                            stdout.write_all(b"\n")?;
+                        }
+                        stdout.flush()?;
+                        Ok(())
+                    }
+                    Command::Add {
+                        service,
+                        email,
+                        password,
+                        username,
+                        url,
+                        custom,
+                    } => {
+                        let mut entry = Entry::new(service, email, password, username, url);
+                        for (name, value) in &custom {
+                            entry.set_custom(name, value.expose())?;
+                        }
+                        let vault = Vault::unlock(&mut credentials)?;
+                        vault.add(entry)?;
+                        println!("Entry added: OK");
+                        Ok(())
+                    }
+                    Command::AddSshKey { service, file } => {
+                        let private_key = std::fs::read_to_string(&file)
+                            .map(Zeroizing::new)
+                            .map_err(|e| {
+                                anyhow::Error::new(e)
+                                    .context(format!("Can't read SSH key '{}'", file.display()))
+                            })?;
+                        let entry = Entry::ssh_key(service, &private_key)?;
+                        let vault = Vault::unlock(&mut credentials)?;
+                        vault.add(entry)?;
+                        println!("SSH key added: OK");
+                        Ok(())
+                    }
+                    Command::Update {
+                        id,
+                        service,
+                        email,
+                        password,
+                        username,
+                        url,
+                        custom,
+                    } => {
+                        let vault = Vault::unlock(&mut credentials)?;
+                        vault.update(&id, service, email, password, username, url, &custom)?;
+                        println!("Entry updated: OK");
+                        Ok(())
+                    }
+                    Command::Rm { id } => {
+                        let vault = Vault::unlock(&mut credentials)?;
+                        vault.remove(&id)?;
+                        println!("Entry deleted: OK");
+                        Ok(())
+                    }
+                    Command::Import { file } => {
+                        let mut vault = Vault::unlock(&mut credentials)?;
+                        vault.import(file)?;
+                        println!("Import: OK");
+                        Ok(())
+                    }
+                    Command::Export { file } => {
+                        let vault = Vault::unlock(&mut credentials)?;
+                        vault.export(file)?;
+                        println!("Export: OK");
+                        Ok(())
                     }
-                    Ok(())
                 }
-            },
+            }
+        }
+    }
+}
+
+/// Opens the TUI on `entries`, or writes them to stdout in `format`. Without a
+/// terminal to open the TUI on, they are written as plain text.
+fn show(entries: Vec<Entry>, output: OutputArgs) -> Result<(), anyhow::Error> {
+    let OutputArgs {
+        format,
+        fields,
+        reveal,
+    } = output;
+    let format = format.or((!std::io::stdout().is_terminal()).then_some(Format::Plain));
+    match format {
+        Some(format) => {
+            print!("{}", render(&entries, format, &fields, reveal)?.as_str());
+            Ok(())
+        }
+        None if !fields.is_empty() || reveal => Err(CandadoError::InvalidInput(
+            "--fields and --reveal only apply to written output -> Add --format.".to_owned(),
+        )
+        .into()),
+        None => tui::init(App::Table(TableApp::new(entries)?)),
+    }
+}
+
+/// Forks a process that detaches from the terminal and serves `agent`, so
+/// closing the shell that started it does not stop it. Returns the pid of the
+/// agent.
+fn spawn_detached(agent: Agent) -> Result<i32, anyhow::Error> {
+    // SAFETY: the CLI starts no threads, so the child starts in a consistent
+    // state.
+    match unsafe { libc::fork() } {
+        -1 => Err(std::io::Error::last_os_error().into()),
+        0 => {
+            detach();
+            let result = agent.serve();
+            std::process::exit(if result.is_ok() { 0 } else { 1 })
+        }
+        pid => Ok(pid),
+    }
+}
+
+/// Starts a new session without a terminal and points stdin, stdout and
+/// stderr at /dev/null.
+fn detach() {
+    // SAFETY: plain syscalls on descriptors owned by this process.
+    unsafe {
+        libc::setsid();
+    }
+    if let Ok(null) = File::options().read(true).write(true).open("/dev/null") {
+        for fd in 0..3 {
+            // SAFETY: both descriptors are valid for the duration of the call.
+            unsafe {
+                libc::dup2(null.as_raw_fd(), fd);
+            }
         }
     }
 }
 
-fn main() -> Result<(), anyhow::Error> {
-    CandadoCLI::run()
+/// Splits a `--custom` argument into field name and value.
+fn parse_custom(arg: &str) -> Result<(String, SecretString), String> {
+    arg.split_once('=')
+        .map(|(name, value)| (name.to_owned(), SecretString::from(value)))
+        .ok_or_else(|| format!("expected NAME=VALUE, got '{arg}'"))
+}
+
+fn print_recovery_key(recovery_key: &RecoveryKey) {
+    println!("Recovery key, it is shown only once! Write it down and keep it in a safe location:");
+    print_words(&recovery_key.phrase());
+    println!("Use 'candado vault recover' with these words to set a new master.");
+}
+
+fn print_words(phrase: &SecretString) {
+    let words: Vec<&str> = phrase.expose().split(' ').collect();
+    for (row, chunk) in words.chunks(5).enumerate() {
+        let line: Vec<String> = chunk
+            .iter()
+            .enumerate()
+            .map(|(i, word)| format!("{:>2}. {word:<10}", row * 5 + i + 1))
+            .collect();
+        println!("  {}", line.join(" ").trim_end());
+    }
+}
+
+const EXIT_CODES: &str = "Exit codes:
+  0  success
+  1  unexpected error
+  2  invalid input or arguments
+  3  entry not found
+  4  authentication failed
+  5  I/O error
+  6  no vault initialized
+  7  corrupt vault or failed integrity check
+  8  database error
+  9  vault locked and no terminal to ask for the master
+  10 query matches more than one entry
+  11 entry has no such field";
+
+/// Exit codes, one per kind of [`CandadoError`]. Clap exits with
+/// [`exit::INVALID_INPUT`] on bad arguments as well. Keep in sync with
+/// [`EXIT_CODES`] and the README.
+mod exit {
+    pub const FAILURE: u8 = 1;
+    pub const INVALID_INPUT: u8 = 2;
+    pub const NOT_FOUND: u8 = 3;
+    pub const AUTH_FAILED: u8 = 4;
+    pub const IO: u8 = 5;
+    pub const NOT_INITIALIZED: u8 = 6;
+    pub const CORRUPT: u8 = 7;
+    pub const DATABASE: u8 = 8;
+    pub const LOCKED: u8 = 9;
+    pub const AMBIGUOUS: u8 = 10;
+    pub const MISSING_FIELD: u8 = 11;
+}
+
+fn exit_code(err: &anyhow::Error) -> u8 {
+    match CandadoError::find(err) {
+        Some(CandadoError::InvalidInput(_)) => exit::INVALID_INPUT,
+        Some(CandadoError::NotFound(_)) => exit::NOT_FOUND,
+        Some(CandadoError::MissingField { .. }) => exit::MISSING_FIELD,
+        Some(CandadoError::Ambiguous { .. }) => exit::AMBIGUOUS,
+        Some(CandadoError::AuthFailed(_)) => exit::AUTH_FAILED,
+        Some(CandadoError::Io(_)) => exit::IO,
+        Some(CandadoError::NotInitialized(_)) => exit::NOT_INITIALIZED,
+        Some(CandadoError::Corrupt(_)) => exit::CORRUPT,
+        Some(CandadoError::Database(_)) => exit::DATABASE,
+        Some(CandadoError::Locked(_)) => exit::LOCKED,
+        // Errors that were not classified by candadolib
+        None if err.chain().any(|cause| cause.is::<std::io::Error>()) => exit::IO,
+        None if err.chain().any(|cause| cause.is::<rusqlite::Error>()) => exit::DATABASE,
+        None => exit::FAILURE,
+    }
+}
+
+fn main() -> ExitCode {
+    harden_process();
+    match CandadoCLI::run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e:#}");
+            ExitCode::from(exit_code(&e))
+        }
+    }
 }
@@ -0,0 +1,119 @@
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{AeadCore, Aes256Gcm, KeyInit};
+use anyhow::{anyhow, Result};
+use argon2::password_hash::rand_core::OsRng;
+use chacha20poly1305::XChaCha20Poly1305;
+use core::fmt;
+use std::str::FromStr;
+
+use crate::secret::SecretBytes;
+
+/// AEAD algorithms a vault can be encrypted with. The choice is recorded in
+/// the keyfile and applies to the data key and every entry.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CipherSuite {
+    #[default]
+    Aes256Gcm,
+    XChaCha20Poly1305,
+}
+
+impl CipherSuite {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            CipherSuite::Aes256Gcm => "aes-256-gcm",
+            CipherSuite::XChaCha20Poly1305 => "xchacha20-poly1305",
+        }
+    }
+
+    const fn nonce_len(&self) -> usize {
+        match self {
+            CipherSuite::Aes256Gcm => 12,
+            CipherSuite::XChaCha20Poly1305 => 24,
+        }
+    }
+}
+
+impl FromStr for CipherSuite {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "aes-256-gcm" => Ok(CipherSuite::Aes256Gcm),
+            "xchacha20-poly1305" => Ok(CipherSuite::XChaCha20Poly1305),
+            _ => Err(anyhow!(
+                "Unsupported cipher '{s}', expected aes-256-gcm or xchacha20-poly1305"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for CipherSuite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A keyed instance of a [`CipherSuite`].
+#[derive(Clone)]
+pub enum Cipher {
+    Aes256Gcm(Box<Aes256Gcm>),
+    XChaCha20Poly1305(XChaCha20Poly1305),
+}
+
+impl Cipher {
+    pub fn new(suite: CipherSuite, key: &SecretBytes) -> Result<Self> {
+        let cipher = match suite {
+            CipherSuite::Aes256Gcm => Cipher::Aes256Gcm(Box::new(
+                Aes256Gcm::new_from_slice(key.expose())
+                    .map_err(|_| anyhow!("Invalid key length"))?,
+            )),
+            CipherSuite::XChaCha20Poly1305 => Cipher::XChaCha20Poly1305(
+                XChaCha20Poly1305::new_from_slice(key.expose())
+                    .map_err(|_| anyhow!("Invalid key length"))?,
+            ),
+        };
+        Ok(cipher)
+    }
+
+    pub fn suite(&self) -> CipherSuite {
+        match self {
+            Cipher::Aes256Gcm(_) => CipherSuite::Aes256Gcm,
+            Cipher::XChaCha20Poly1305(_) => CipherSuite::XChaCha20Poly1305,
+        }
+    }
+
+    /// Encrypts `msg` under a fresh random nonce. Returns `(nonce, ciphertext)`.
+    pub fn seal(&self, msg: &[u8], aad: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        let payload = Payload { msg, aad };
+        let sealed = match self {
+            Cipher::Aes256Gcm(cipher) => {
+                let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+                cipher
+                    .encrypt(&nonce, payload)
+                    .map(|msg| (nonce.to_vec(), msg))
+            }
+            Cipher::XChaCha20Poly1305(cipher) => {
+                let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+                cipher
+                    .encrypt(&nonce, payload)
+                    .map(|msg| (nonce.to_vec(), msg))
+            }
+        };
+        sealed.map_err(|e| anyhow!("Failed to encrypt data: {e}"))
+    }
+
+    pub fn open(&self, nonce: &[u8], msg: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if nonce.len() != self.suite().nonce_len() {
+            return Err(anyhow!(
+                "Failed to decrypt data: nonce does not match {}",
+                self.suite()
+            ));
+        }
+        let payload = Payload { msg, aad };
+        let opened = match self {
+            Cipher::Aes256Gcm(cipher) => cipher.decrypt(nonce.into(), payload),
+            Cipher::XChaCha20Poly1305(cipher) => cipher.decrypt(nonce.into(), payload),
+        };
+        opened.map_err(|_| anyhow!("Failed to decrypt data: authentication failed"))
+    }
+}
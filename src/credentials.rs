@@ -0,0 +1,165 @@
+use anyhow::{anyhow, Result};
+use core::fmt;
+use crossterm::style::Stylize;
+use rpassword::prompt_password;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::fd::{FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+use crate::vault_dir::VaultDir;
+use crate::{SecretString, PREFIX};
+
+/// Environment variable naming an inherited file descriptor to read the
+/// master from, e.g. `CANDADO_MASTER_FD=3 candado vault ls 3<master.txt`.
+pub const MASTER_FD_ENV: &str = "CANDADO_MASTER_FD";
+
+/// Where the master and other secrets are read from.
+///
+/// There is deliberately no way to pass the master as a plain argument, it
+/// would end up in the shell history and in the process list of every user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MasterSource {
+    /// Ask on the terminal.
+    Prompt,
+    /// Read lines from standard input.
+    Stdin,
+    /// Read lines from a file.
+    File(PathBuf),
+    /// Read lines from an inherited file descriptor.
+    Fd(RawFd),
+}
+
+impl MasterSource {
+    /// Picks the source from the command line flags, falling back to the
+    /// descriptor in [`MASTER_FD_ENV`] and then to the terminal.
+    pub fn select(stdin: bool, file: Option<PathBuf>) -> Result<Self> {
+        if stdin {
+            return Ok(MasterSource::Stdin);
+        }
+        if let Some(file) = file {
+            return Ok(MasterSource::File(file));
+        }
+        match std::env::var(MASTER_FD_ENV) {
+            Ok(fd) => fd.trim().parse().map(MasterSource::Fd).map_err(|_| {
+                anyhow!("{MASTER_FD_ENV} must be a file descriptor number, got '{fd}'")
+            }),
+            Err(_) => Ok(MasterSource::Prompt),
+        }
+    }
+}
+
+impl fmt::Display for MasterSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MasterSource::Prompt => f.write_str("the terminal"),
+            MasterSource::Stdin => f.write_str("standard input"),
+            MasterSource::File(path) => write!(f, "'{}'", path.display()),
+            MasterSource::Fd(fd) => write!(f, "file descriptor {fd}"),
+        }
+    }
+}
+
+/// Everything needed to unlock a vault: which vault, where the master comes
+/// from and the key file, if the vault uses one.
+///
+/// Sources other than the terminal are read once up to the end. Every prompt
+/// then takes the next line instead, and new masters are not asked twice.
+pub struct Credentials {
+    vault: VaultDir,
+    source: MasterSource,
+    lines: Option<VecDeque<SecretString>>,
+    key_file: Option<PathBuf>,
+}
+
+impl Credentials {
+    pub fn new(vault: VaultDir, source: MasterSource, key_file: Option<PathBuf>) -> Self {
+        Self {
+            vault,
+            source,
+            lines: None,
+            key_file,
+        }
+    }
+
+    pub fn vault(&self) -> &VaultDir {
+        &self.vault
+    }
+
+    pub fn key_file(&self) -> Option<&Path> {
+        self.key_file.as_deref()
+    }
+
+    pub fn is_interactive(&self) -> bool {
+        self.source == MasterSource::Prompt
+    }
+
+    /// Reads the next secret, asking with `prompt` on the terminal.
+    pub fn read(&mut self, prompt: &str) -> Result<SecretString> {
+        if self.is_interactive() {
+            return Ok(SecretString::from(prompt_password(format!(
+                "{} {prompt}: ",
+                PREFIX.green()
+            ))?));
+        }
+
+        if self.lines.is_none() {
+            self.lines = Some(self.load()?);
+        }
+        self.lines
+            .as_mut()
+            .and_then(|lines| lines.pop_front())
+            .ok_or_else(|| {
+                anyhow!(
+                    "Nothing left to read from {} -> Expected a line for '{prompt}'.",
+                    self.source
+                )
+            })
+    }
+
+    /// Reads a new master, which has to be entered twice on the terminal.
+    pub fn read_new(&mut self, prompt: &str) -> Result<SecretString> {
+        let new = self.read(prompt)?;
+        if self.is_interactive() {
+            let confirm = self.read("Confirm new master")?;
+            if new != confirm {
+                return Err(anyhow!("Masters do not match."));
+            }
+        }
+        Ok(new)
+    }
+
+    fn load(&self) -> Result<VecDeque<SecretString>> {
+        let mut content = Zeroizing::new(String::new());
+        match &self.source {
+            MasterSource::Prompt => unreachable!("the terminal is never read ahead"),
+            MasterSource::Stdin => {
+                io::stdin().read_to_string(&mut content)?;
+            }
+            MasterSource::File(path) => {
+                File::open(path)
+                    .and_then(|mut file| file.read_to_string(&mut content))
+                    .map_err(|e| anyhow!("Can't read password file '{}': {e}", path.display()))?;
+            }
+            MasterSource::Fd(fd) => {
+                // Refuse the standard streams, closing them would break output
+                if *fd < 3 {
+                    return Err(anyhow!(
+                        "Invalid master file descriptor {fd} -> Use --password-stdin instead."
+                    ));
+                }
+                // SAFETY: F_GETFD only inspects the descriptor table.
+                if unsafe { libc::fcntl(*fd, libc::F_GETFD) } == -1 {
+                    return Err(anyhow!("Master file descriptor {fd} is not open"));
+                }
+                // SAFETY: the descriptor was handed to candado to read the master
+                // from and is open, it is closed once read.
+                let mut file = unsafe { File::from_raw_fd(*fd) };
+                file.read_to_string(&mut content)?;
+            }
+        }
+        Ok(content.lines().map(SecretString::from).collect())
+    }
+}
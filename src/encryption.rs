@@ -1,5 +1,3 @@
-use aes_gcm::aead::Aead;
-use aes_gcm::{AeadCore, Aes256Gcm, Key, KeyInit, Nonce};
 use anyhow::anyhow;
 use anyhow::Result;
 use argon2::{
@@ -9,95 +7,376 @@ use argon2::{
 use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
 use rand::RngCore;
-use std::fs::{self, set_permissions, File, Permissions};
-use std::io::Write;
-use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
+use zeroize::Zeroizing;
 
-use crate::PROGRAM_FOLDER;
+use crate::agent::Session;
+use crate::cipher::{Cipher, CipherSuite};
+use crate::error::CandadoError;
+use crate::factor::load_key_file;
+use crate::keyfile::{KdfCost, KdfParams, Keyfile, KEYFILE_VERSION};
+use crate::recovery::RecoveryKey;
+use crate::secret::SecretBytes;
+use crate::vault_dir::VaultDir;
 
+/// An unlocked vault key.
+///
+/// The data key is unwrapped once at [`Encrypter::unlock`] and kept as a ready
+/// cipher, so encrypting and decrypting fields never touches the keyfile
+/// or the derived key again. An encrypter fetched from the agent holds no key
+/// at all and has the agent encrypt and decrypt fields instead.
 #[derive(Clone)]
 pub struct Encrypter {
-    pub derived_key: Vec<u8>,
-    pub encrpytion_key: String,
+    key: Key,
+    keyfile: Keyfile,
+    vault: VaultDir,
+}
+
+#[derive(Clone)]
+enum Key {
+    /// Unwrapped in this process with the master.
+    Local(LocalKey),
+    /// Held by the agent, which never hands it out.
+    Agent {
+        session: Arc<Session>,
+        suite: CipherSuite,
+    },
+}
+
+#[derive(Clone)]
+struct LocalKey {
+    cipher: Cipher,
+    data_key: SecretBytes,
+    derived_key: SecretBytes,
 }
 
 impl Encrypter {
-    pub fn init(master: &str) -> Result<()> {
+    /// Creates the keyfile of a new vault in `vault`. With `recovery` a
+    /// recovery key is generated that can set a new master if this one is
+    /// lost, with `key_file` the contents of that file are needed next to the
+    /// master.
+    pub fn init(
+        vault: &VaultDir,
+        master: &str,
+        key_file: Option<&Path>,
+        cost: &KdfCost,
+        suite: CipherSuite,
+        recovery: bool,
+    ) -> Result<Option<RecoveryKey>> {
+        cost.validate()?;
+        let factor = key_file.map(load_key_file).transpose()?;
+
         // Generate Salt
         let mut salt = [0u8; 16];
         OsRng.fill_bytes(&mut salt);
-
-        // derive password
-        let derived_key = Encrypter::derive(&salt, master)?;
-        // Hash derived key
-        let derived_hash = Encrypter::hash(&STANDARD.encode(&derived_key))?;
+        let mut keyfile = Keyfile::new(suite, KdfParams::new(&salt, cost));
 
         // Gen encryption key
-        let rkey = Aes256Gcm::generate_key(OsRng);
-        let dkey = Key::<Aes256Gcm>::from_slice(&derived_key);
-        let cypher = Aes256Gcm::new(dkey);
-        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-
-        let ekey = cypher
-            .encrypt(&nonce, &rkey[..])
-            .map_err(|e| anyhow!("Error generating enryption key: {e}"))?;
-        let ekey = format!("{}:{}", STANDARD.encode(nonce), STANDARD.encode(ekey));
-        let encoded_salt = STANDARD.encode(&salt);
+        let rkey = Encrypter::generate_key();
+        let recovery_key = recovery.then(RecoveryKey::generate);
+        keyfile.recovery = recovery_key
+            .as_ref()
+            .map(|recovery_key| Encrypter::wrap_key(suite, recovery_key.secret(), &rkey))
+            .transpose()?;
 
         // Write keyfile
-        Encrypter::write_keyfile(encoded_salt, derived_hash, ekey)?;
-        Ok(())
+        Encrypter::lock(keyfile, master, factor.as_ref(), &rkey)?.write(&vault.keyfile())?;
+        Ok(recovery_key)
+    }
+
+    /// Replaces the master of an existing vault.
+    ///
+    /// The old master is verified, a new key is derived with a fresh salt and
+    /// the data key is re-wrapped with it. Entries are left untouched since the
+    /// data key itself does not change. A key file stays required.
+    pub fn change_master(
+        vault: &VaultDir,
+        old: &str,
+        new: &str,
+        key_file: Option<&Path>,
+    ) -> Result<()> {
+        let encrypter = Encrypter::unlock(vault, old, key_file)?;
+        let factor = key_file.map(load_key_file).transpose()?;
+        let keyfile = Keyfile {
+            kdf: encrypter.keyfile.kdf.resalt(),
+            ..encrypter.keyfile.clone()
+        };
+        let keyfile = Encrypter::lock(keyfile, new, factor.as_ref(), encrypter.master_key()?)?;
+        let _lock = vault.lock()?;
+        encrypter.check_current()?;
+        keyfile.write(&vault.keyfile())
+    }
+
+    /// Sets a new master using the recovery key of the vault, or the secret
+    /// restored from its recovery shares.
+    ///
+    /// Like [`Encrypter::change_master`] only the wrapping of the data key
+    /// changes, the recovery key and shares keep working afterwards. The key
+    /// file may be lost as well, so the vault requires `key_file` from now on
+    /// and no key file if it is `None`.
+    pub fn recover(
+        vault: &VaultDir,
+        recovery_key: &RecoveryKey,
+        new: &str,
+        key_file: Option<&Path>,
+    ) -> Result<()> {
+        // Without a vault there is nothing to lock, loading the keyfile fails
+        let _lock = vault.exists().then(|| vault.lock()).transpose()?;
+        let keyfile = Encrypter::load_keyfile(vault)?;
+        let slots: Vec<&String> = [&keyfile.recovery, &keyfile.shares]
+            .into_iter()
+            .flatten()
+            .collect();
+        if slots.is_empty() {
+            return Err(anyhow!(
+                "This vault has no recovery key or shares -> Access can't be recovered."
+            ));
+        }
+
+        let suite = keyfile.cipher.parse()?;
+        let Some(rkey) = slots
+            .iter()
+            .find_map(|slot| Encrypter::unwrap_key(suite, recovery_key.secret(), slot).ok())
+        else {
+            std::thread::sleep(Duration::new(5, 0));
+            return Err(CandadoError::AuthFailed("Wrong recovery key.".to_owned()).into());
+        };
+
+        let factor = key_file.map(load_key_file).transpose()?;
+        let keyfile = Keyfile {
+            kdf: keyfile.kdf.resalt(),
+            ..keyfile.clone()
+        };
+        Encrypter::lock(keyfile, new, factor.as_ref(), &rkey)?.write(&vault.keyfile())
     }
 
-    pub fn unlock(master: &str) -> Result<Self> {
-        let (salt, hash, ekey) = match Encrypter::load_keyfile_path() {
-            Ok(keyfile) => Encrypter::load_keyfile(keyfile)?,
-            Err(e) => {
-                return Err(anyhow!(
-                    "{e} -> Use 'candado vault init' to initialize a new vault."
-                ));
+    pub fn unlock(vault: &VaultDir, master: &str, key_file: Option<&Path>) -> Result<Self> {
+        let keyfile = Encrypter::load_keyfile(vault)?;
+        let factor = match (keyfile.key_file, key_file) {
+            (true, Some(path)) => Some(load_key_file(path)?),
+            (true, None) => {
+                return Err(CandadoError::AuthFailed(
+                    "This vault needs its key file, use --keyfile PATH to unlock it.".to_owned(),
+                )
+                .into());
+            }
+            (false, Some(_)) => {
+                return Err(CandadoError::InvalidInput(
+                    "This vault doesn't use a key file.".to_owned(),
+                )
+                .into())
             }
+            (false, None) => None,
         };
 
-        let dkey = Encrypter::derive(&salt, master)?;
-        if !Encrypter::verify(&hash, &STANDARD.encode(&dkey)) {
+        let dkey = Encrypter::derive(&keyfile.kdf, master, factor.as_ref())?;
+        if !Encrypter::verify(&keyfile.hash, &dkey)? {
             std::thread::sleep(Duration::new(5, 0));
-            return Err(anyhow!("Authentication Failed -> Wrong password."));
+            let reason = match factor {
+                Some(_) => "Wrong password or key file.",
+                None => "Wrong password.",
+            };
+            return Err(CandadoError::AuthFailed(reason.to_owned()).into());
+        }
+
+        let upgrade = !keyfile.is_current();
+        let encrypter = Encrypter::new(vault.clone(), dkey, keyfile)?;
+
+        // Older keyfiles are upgraded in place once we know the master is right
+        if upgrade {
+            encrypter.write_keyfile()?;
         }
+        Ok(encrypter)
+    }
 
+    /// Wraps a session with the agent of `vault`, which does the encryption
+    /// for this encrypter.
+    pub(crate) fn attach(vault: &VaultDir, session: Session) -> Result<Self> {
+        let keyfile = Encrypter::load_keyfile(vault)?;
         Ok(Self {
-            derived_key: dkey,
-            encrpytion_key: ekey,
+            key: Key::Agent {
+                session: Arc::new(session),
+                suite: keyfile.cipher.parse()?,
+            },
+            keyfile,
+            vault: vault.clone(),
         })
     }
 
-    fn hash(password: &str) -> Result<String> {
+    /// Rebuilds this encrypter from the keyfile on disk, picking up a rotated
+    /// data key. Fails if the keyfile changed in a way the derived key can't
+    /// open, e.g. after the master was changed.
+    pub(crate) fn reload(&self) -> Result<Self> {
+        let local = self.local()?;
+        Encrypter::new(
+            self.vault.clone(),
+            local.derived_key.clone(),
+            Encrypter::load_keyfile(&self.vault)?,
+        )
+    }
+
+    /// The keyfiles staged by rotations that never swapped them in, each with
+    /// the encrypter it opens if the derived key of this one unwraps it.
+    pub(crate) fn staged_rotations(&self) -> Result<Vec<(PathBuf, Option<Self>)>> {
+        let local = self.local().ok();
+        let rotations = Keyfile::rotations(&self.vault.keyfile())?;
+        Ok(rotations
+            .into_iter()
+            .map(|staged| {
+                let rotated = local.and_then(|local| {
+                    let keyfile = Keyfile::load(&staged).ok()?;
+                    Encrypter::new(self.vault.clone(), local.derived_key.clone(), keyfile).ok()
+                });
+                (staged, rotated)
+            })
+            .collect())
+    }
+
+    /// Fails unless the keyfile on disk still wraps the data key the way this
+    /// encrypter does. It doesn't once another process changed the master or
+    /// rotated the key after this one was unlocked, and writing this keyfile
+    /// would undo that. Call it holding the lock of the vault.
+    pub(crate) fn check_current(&self) -> Result<()> {
+        if Encrypter::load_keyfile(&self.vault)?.key != self.keyfile.key {
+            return Err(anyhow!(
+                "The keyfile changed since the vault was unlocked -> Unlock it again and retry."
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns a copy of this encrypter holding a freshly generated data key
+    /// for `suite`, which may differ from the current one.
+    ///
+    /// Nothing is persisted, the keyfile keeps the current data key until the
+    /// rotated encrypter is written with [`Encrypter::stage_keyfile`] and
+    /// [`Encrypter::commit_keyfile`]. The recovery key and shares can't unwrap
+    /// the new data key, so the copy has none until they are added again.
+    pub fn rotate(&self, suite: CipherSuite) -> Result<Self> {
+        let local = self.local()?;
+        let rkey = Encrypter::generate_key();
+        let keyfile = Keyfile {
+            cipher: suite.as_str().to_owned(),
+            key: Encrypter::wrap_key(suite, &local.derived_key, &rkey)?,
+            recovery: None,
+            shares: None,
+            ..self.keyfile.clone()
+        };
+        Encrypter::new(self.vault.clone(), local.derived_key.clone(), keyfile)
+    }
+
+    /// Generates a recovery key for the data key, replacing any previous one.
+    /// It is persisted with the next keyfile write.
+    pub fn add_recovery(&mut self) -> Result<RecoveryKey> {
+        let recovery_key = RecoveryKey::generate();
+        let wrapped = Encrypter::wrap_key(self.suite(), recovery_key.secret(), self.master_key()?)?;
+        self.keyfile.recovery = Some(wrapped);
+        Ok(recovery_key)
+    }
+
+    /// Generates the secret that is split into recovery shares, replacing any
+    /// previous one. It is persisted with the next keyfile write.
+    pub fn add_shares(&mut self) -> Result<RecoveryKey> {
+        let secret = RecoveryKey::generate();
+        let wrapped = Encrypter::wrap_key(self.suite(), secret.secret(), self.master_key()?)?;
+        self.keyfile.shares = Some(wrapped);
+        Ok(secret)
+    }
+
+    pub fn has_recovery(&self) -> bool {
+        self.keyfile.recovery.is_some()
+    }
+
+    pub fn has_shares(&self) -> bool {
+        self.keyfile.shares.is_some()
+    }
+
+    pub fn has_key_file(&self) -> bool {
+        self.keyfile.key_file
+    }
+
+    fn new(vault: VaultDir, derived_key: SecretBytes, keyfile: Keyfile) -> Result<Self> {
+        let suite = keyfile.cipher.parse()?;
+        let data_key = Encrypter::unwrap_key(suite, &derived_key, &keyfile.key)?;
+        Ok(Self {
+            key: Key::Local(LocalKey {
+                cipher: Cipher::new(suite, &data_key)?,
+                data_key,
+                derived_key,
+            }),
+            keyfile: Keyfile {
+                version: KEYFILE_VERSION,
+                ..keyfile
+            },
+            vault,
+        })
+    }
+
+    /// Wraps `rkey` with `master` and the optional key file hash, using the
+    /// kdf settings and cipher of `keyfile`.
+    fn lock(
+        keyfile: Keyfile,
+        master: &str,
+        factor: Option<&SecretBytes>,
+        rkey: &SecretBytes,
+    ) -> Result<Keyfile> {
+        let suite = keyfile.cipher.parse()?;
+        // derive password
+        let derived_key = Encrypter::derive(&keyfile.kdf, master, factor)?;
+        Ok(Keyfile {
+            version: KEYFILE_VERSION,
+            // Hash derived key
+            hash: Encrypter::hash(&derived_key)?,
+            key: Encrypter::wrap_key(suite, &derived_key, rkey)?,
+            key_file: factor.is_some(),
+            ..keyfile
+        })
+    }
+
+    fn hash(derived_key: &SecretBytes) -> Result<String> {
         let argon2 = Argon2::default();
         let salt = SaltString::generate(&mut OsRng);
+        let encoded = Zeroizing::new(STANDARD.encode(derived_key.expose()));
         let hash = argon2
-            .hash_password(password.as_bytes(), &salt)
+            .hash_password(encoded.as_bytes(), &salt)
             .map_err(|e| anyhow!("Failed to hash password: {e}"))?
             .to_string();
         Ok(hash)
     }
 
-    fn verify(hash: &str, key: &str) -> bool {
+    /// Whether `derived_key` matches the keyfile `hash`. A hash that can't be
+    /// parsed is reported as a corrupt keyfile.
+    fn verify(hash: &str, derived_key: &SecretBytes) -> Result<bool> {
         let argon2 = Argon2::default();
-        let parsed = PasswordHash::new(hash)
-            .map_err(|e| anyhow!("Error verifing hash: {e}"))
-            .expect(&format!("Invalid password hash: {hash}"));
-        argon2.verify_password(key.as_bytes(), &parsed).is_ok()
+        let parsed = PasswordHash::new(hash).map_err(|e| {
+            CandadoError::Corrupt(format!(
+                "Keyfile hash is malformed: {e} -> Use 'candado vault check' to inspect the keyfile."
+            ))
+        })?;
+        let encoded = Zeroizing::new(STANDARD.encode(derived_key.expose()));
+        Ok(argon2.verify_password(encoded.as_bytes(), &parsed).is_ok())
     }
 
-    fn derive(salt: &[u8], master: &str) -> Result<Vec<u8>> {
-        let argon2 = Argon2::default();
-        let mut derived_key = [0u8; 32];
+    /// Derives the key that wraps the data key. The key file hash, if any, is
+    /// passed to Argon2 as secret so the master alone derives a different key.
+    fn derive(kdf: &KdfParams, master: &str, factor: Option<&SecretBytes>) -> Result<SecretBytes> {
+        let argon2 = match factor {
+            Some(factor) => kdf.argon2_with_secret(factor.expose())?,
+            None => kdf.argon2()?,
+        };
+        let mut derived_key = Zeroizing::new([0u8; 32]);
         argon2
-            .hash_password_into(master.as_bytes(), salt, &mut derived_key)
+            .hash_password_into(master.as_bytes(), &kdf.salt()?, derived_key.as_mut())
             .map_err(|e| anyhow!("Error deriving password: {e}"))?;
-        Ok(derived_key.to_vec())
+        Ok(SecretBytes::from(&derived_key[..]))
+    }
+
+    fn generate_key() -> SecretBytes {
+        let mut rkey = Zeroizing::new([0u8; 32]);
+        OsRng.fill_bytes(rkey.as_mut());
+        SecretBytes::from(&rkey[..])
     }
 
     pub fn decompose(encrypted: &str) -> Result<(&str, &str)> {
@@ -108,102 +387,130 @@ impl Encrypter {
         }
     }
 
-    pub fn master_key(&self) -> Result<Key<Aes256Gcm>> {
-        let dkey = Key::<Aes256Gcm>::from_slice(&self.derived_key);
-        let cypher = Aes256Gcm::new(dkey);
-        let (nonce, key) = Encrypter::decompose(&self.encrpytion_key)?;
+    fn seal(cipher: &Cipher, msg: &[u8], aad: &[u8]) -> Result<String> {
+        let (nonce, payload) = cipher.seal(msg, aad)?;
+        Ok(format!(
+            "{}:{}",
+            STANDARD.encode(nonce),
+            STANDARD.encode(payload)
+        ))
+    }
+
+    fn open(cipher: &Cipher, sealed: &str, aad: &[u8]) -> Result<Vec<u8>> {
+        let (nonce, msg) = Encrypter::decompose(sealed)?;
         let nonce = STANDARD.decode(nonce)?;
-        let key = STANDARD.decode(key)?;
-        let rkey = cypher
-            .decrypt(Nonce::from_slice(&nonce), key.as_ref())
+        let msg = STANDARD.decode(msg)?;
+        cipher.open(&nonce, &msg, aad)
+    }
+
+    fn wrap_key(
+        suite: CipherSuite,
+        derived_key: &SecretBytes,
+        rkey: &SecretBytes,
+    ) -> Result<String> {
+        let cypher = Cipher::new(suite, derived_key)?;
+        Encrypter::seal(&cypher, rkey.expose(), b"")
+            .map_err(|e| anyhow!("Error generating enryption key: {e}"))
+    }
+
+    fn unwrap_key(
+        suite: CipherSuite,
+        derived_key: &SecretBytes,
+        ekey: &str,
+    ) -> Result<SecretBytes> {
+        let cypher = Cipher::new(suite, derived_key)?;
+        let rkey = Encrypter::open(&cypher, ekey, b"")
             .map_err(|e| anyhow!("Failed to decrypt ekey: {e}"))?;
-        Ok(*Key::<Aes256Gcm>::from_slice(&rkey))
+        Ok(SecretBytes::new(rkey))
+    }
+
+    /// The keys unwrapped with the master, which an encrypter of the agent
+    /// doesn't have.
+    fn local(&self) -> Result<&LocalKey> {
+        match &self.key {
+            Key::Local(local) => Ok(local),
+            Key::Agent { .. } => Err(anyhow!(
+                "The agent doesn't hand out the vault key -> Enter the master to do this."
+            )),
+        }
+    }
+
+    pub fn master_key(&self) -> Result<&SecretBytes> {
+        Ok(&self.local()?.data_key)
+    }
+
+    pub fn suite(&self) -> CipherSuite {
+        match &self.key {
+            Key::Local(local) => local.cipher.suite(),
+            Key::Agent { suite, .. } => *suite,
+        }
+    }
+
+    /// The folder of the vault this encrypter unlocks.
+    pub fn vault(&self) -> &VaultDir {
+        &self.vault
     }
 
     pub fn decrypt(&self, payload: &[u8]) -> Result<String> {
-        let rkey = self.master_key()?;
-        let cypher = Aes256Gcm::new(&rkey);
-        let content = String::from_utf8_lossy(&payload).to_string();
-        let (nonce, msg) = Encrypter::decompose(&content)?;
-        let msg = STANDARD.decode(msg)?;
-        let nonce = STANDARD.decode(nonce)?;
-        let nonce = Nonce::from_slice(&nonce);
-        let plain = cypher
-            .decrypt(&nonce, msg.as_slice())
-            .map_err(|e| anyhow!("Failed to encrypt data: {e}"))?;
-        Ok(String::from_utf8_lossy(&plain).to_string())
+        self.decrypt_with(payload, b"")
     }
 
     pub fn encrypt(&self, plain: &str) -> Result<Vec<u8>> {
-        let rkey = self.master_key()?;
-        let cypher = Aes256Gcm::new(&rkey);
-        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-        let payload = cypher
-            .encrypt(&nonce, plain.as_bytes())
-            .map_err(|e| anyhow!("Failed to encrypt data: {e}"))?;
-        Ok(
-            format!("{}:{}", STANDARD.encode(nonce), STANDARD.encode(payload))
-                .as_bytes()
-                .to_vec(),
-        )
+        self.encrypt_with(plain, b"")
     }
 
-    pub fn load_keyfile_path() -> Result<PathBuf> {
-        // Linux
-        #[cfg(target_os = "linux")]
-        let keypath = format!("{}/.candado/.candado.key", std::env::var("HOME")?);
+    /// Decrypts a payload that was encrypted with `aad` as associated data.
+    /// Fails if the payload was tampered with or written for a different `aad`.
+    pub fn decrypt_with(&self, payload: &[u8], aad: &[u8]) -> Result<String> {
+        let content = String::from_utf8_lossy(payload).to_string();
+        let local = match &self.key {
+            Key::Local(local) => local,
+            Key::Agent { session, .. } => return session.decrypt(&content, aad),
+        };
+        let plain = Zeroizing::new(Encrypter::open(&local.cipher, &content, aad)?);
+        Ok(String::from_utf8_lossy(&plain).to_string())
+    }
 
-        // NOTE: Support for Mac os and Windows will be added in the future
-        // MacOs
-        // #[cfg(target_os = "macos")]
-        // let keypath = format!("{}/.candado/.candado.key", std::env::var("HOME")?);
-        // windows
-        // #[cfg(target_os = "windows")]
-        // let keypath = format!("{}/.passlock/passlock.key", std::env::var("USERHOME")?);
+    /// Encrypts `plain` and binds the ciphertext to `aad`, which has to be
+    /// passed again to decrypt it.
+    pub fn encrypt_with(&self, plain: &str, aad: &[u8]) -> Result<Vec<u8>> {
+        let sealed = match &self.key {
+            Key::Local(local) => Encrypter::seal(&local.cipher, plain.as_bytes(), aad)?,
+            Key::Agent { session, .. } => session.encrypt(plain, aad)?,
+        };
+        Ok(sealed.into_bytes())
+    }
 
-        let keyfile = Path::new(&keypath);
-        if !keyfile.exists() {
-            return Err(anyhow!("Keyfile not found"));
+    fn load_keyfile(vault: &VaultDir) -> Result<Keyfile> {
+        if !vault.exists() {
+            return Err(CandadoError::NotInitialized(vault.path().to_owned()).into());
         }
-        Ok(keyfile.to_path_buf())
-    }
-
-    fn load_keyfile(keyfile: PathBuf) -> Result<(Vec<u8>, String, String)> {
-        let keyfile = fs::read(keyfile)?;
-        let raw = STANDARD.decode(keyfile)?;
-        let content = String::from_utf8_lossy(&raw).to_string();
-        let keys: Vec<&str> = content.splitn(3, ' ').collect();
-        Ok((
-            STANDARD.decode(keys[0])?.to_owned(),
-            keys[1].to_owned(),
-            keys[2].to_owned(),
-        ))
+        Keyfile::load(&vault.keyfile())
     }
 
-    fn write_keyfile(salt: String, hash: String, ekey: String) -> Result<()> {
-        // Linux
-        #[cfg(target_os = "linux")]
-        let dir_path = format!("{}/{}", std::env::var("HOME")?, PROGRAM_FOLDER);
+    /// Checks permissions and structure of the keyfile, returning every
+    /// problem found.
+    pub fn check_keyfile(&self) -> Vec<String> {
+        Keyfile::check(&self.vault.keyfile())
+    }
 
-        // NOTE: Support for Mac os and Windows will be added in the future
-        // MacOs
-        // #[cfg(target_os = "macos")]
-        // let dir_path = format!("{}/.candado", std::env::var("HOME")?);
-        // windows
-        // #[cfg(target_os = "windows")]
-        // let dir_path = format!("{}/.passlock/passlock.key", std::env::var("USERHOME")?);
+    /// Writes the keyfile of this encrypter, replacing the current one. Fails
+    /// if the keyfile changed since this encrypter was unlocked.
+    pub fn write_keyfile(&self) -> Result<()> {
+        let _lock = self.vault.lock()?;
+        self.check_current()?;
+        self.keyfile.write(&self.vault.keyfile())
+    }
 
-        let dir_path = Path::new(&dir_path);
-        if !dir_path.exists() {
-            fs::create_dir(dir_path)?;
-            set_permissions(dir_path, Permissions::from_mode(0o700))?;
-        }
-        let keypath = format!("{}/{}/.candado.key", std::env::var("HOME")?, PROGRAM_FOLDER);
-        let keypath = Path::new(&keypath);
-        let mut keyfile = File::options().write(true).create(true).open(keypath)?;
-        let payload = format!("{} {} {}", salt, hash, ekey);
-        keyfile.write_all(STANDARD.encode(payload).as_bytes())?;
-        set_permissions(keypath, Permissions::from_mode(0o600))?;
-        Ok(())
+    /// Writes the keyfile of this rotated encrypter next to the current one
+    /// without replacing it yet, under a path no other write uses. Returns the
+    /// path of the staged file.
+    pub fn stage_keyfile(&self) -> Result<PathBuf> {
+        self.keyfile.stage_rotation(&self.vault.keyfile())
+    }
+
+    /// Atomically replaces the keyfile with a previously staged one.
+    pub fn commit_keyfile(staged: &Path) -> Result<()> {
+        Keyfile::commit(staged)
     }
 }
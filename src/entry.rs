@@ -1,63 +1,168 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use core::fmt;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use zeroize::{Zeroize, Zeroizing};
 
-use crate::{generators, Encrypter};
+use crate::{error::CandadoError, generators, secret::SecretString, ssh_agent, Encrypter};
 
-#[derive(Debug, Serialize, Default, Clone)]
+#[derive(Serialize, Default, Clone)]
 pub struct Entry {
     pub id: String,
     pub service: String,
     pub email: String,
-    pub password: String,
+    pub password: SecretString,
     pub username: String,
     pub url: String,
+    /// Extra secrets like tokens or pins, by name.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub custom: BTreeMap<String, SecretString>,
+    pub kind: EntryKind,
+}
+
+/// What an entry holds, the fields are used the same way for every kind.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EntryKind {
+    /// Credentials of a service.
+    #[default]
+    Login,
+    /// An unencrypted OpenSSH private key in the password field, served by
+    /// `candado ssh-agent`. The username holds the key comment.
+    SshKey,
+}
+
+impl EntryKind {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            EntryKind::Login => "login",
+            EntryKind::SshKey => "ssh-key",
+        }
+    }
+}
+
+impl FromStr for EntryKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "login" => Ok(EntryKind::Login),
+            "ssh-key" => Ok(EntryKind::SshKey),
+            _ => Err(CandadoError::Corrupt(format!("Unknown entry kind '{s}'")).into()),
+        }
+    }
+}
+
+impl fmt::Display for EntryKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        self.id.zeroize();
+        self.service.zeroize();
+        self.email.zeroize();
+        self.username.zeroize();
+        self.url.zeroize();
+    }
+}
+
+impl fmt::Debug for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Entry")
+            .field("id", &self.id)
+            .field("service", &self.service)
+            .field("email", &self.email)
+            .field("password", &"***")
+            .field("username", &self.username)
+            .field("url", &self.url)
+            .field("custom", &self.custom.keys().collect::<Vec<_>>())
+            .field("kind", &self.kind)
+            .finish()
+    }
 }
 
 pub struct EncryptedEntry {
-    pub id: Vec<u8>,
+    pub id: String,
     pub service: Vec<u8>,
     pub email: Vec<u8>,
     pub password: Vec<u8>,
     pub username: Vec<u8>,
     pub url: Vec<u8>,
+    pub custom: Vec<u8>,
+    pub kind: Vec<u8>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Deserialize)]
 pub struct ImportedEntry {
     pub service: String,
     pub email: String,
-    pub password: String,
+    pub password: SecretString,
     pub username: String,
     pub url: String,
+    #[serde(default)]
+    pub custom: BTreeMap<String, SecretString>,
+    #[serde(default)]
+    pub kind: EntryKind,
 }
 
 impl From<ImportedEntry> for Entry {
     fn from(value: ImportedEntry) -> Self {
-        Entry::new(
-            value.service,
-            value.email,
-            Some(value.password),
-            Some(value.username),
-            Some(value.url),
-        )
+        Self {
+            id: generators::gen_key(12),
+            service: value.service,
+            email: value.email,
+            password: value.password,
+            username: value.username,
+            url: value.url,
+            custom: value.custom,
+            kind: value.kind,
+        }
     }
 }
 
+/// Associated data every field is encrypted with, so a ciphertext only
+/// decrypts in the entry and column it was written for.
+pub fn associated_data(id: &str, field: &str) -> Vec<u8> {
+    format!("candado/{id}/{field}").into_bytes()
+}
+
 pub trait Decrypt {
     fn decrypt(&self, encrypter: &Encrypter) -> Result<Entry>;
 }
 
 impl Decrypt for EncryptedEntry {
     fn decrypt(&self, encrypter: &Encrypter) -> Result<Entry> {
-        Ok(Entry::init(
-            encrypter.decrypt(&self.id)?,
-            encrypter.decrypt(&self.service)?,
-            encrypter.decrypt(&self.email)?,
-            encrypter.decrypt(&self.password)?,
-            encrypter.decrypt(&self.username)?,
-            encrypter.decrypt(&self.url)?,
-        ))
+        let field = |name: &str, payload: &[u8]| {
+            encrypter
+                .decrypt_with(payload, &associated_data(&self.id, name))
+                .with_context(|| {
+                    CandadoError::Corrupt(format!(
+                        "Integrity check failed for entry '{}' field '{name}' -> the vault was tampered with",
+                        self.id
+                    ))
+                })
+        };
+        let mut entry = Entry::init(
+            self.id.clone(),
+            field("service", &self.service)?,
+            field("email", &self.email)?,
+            field("password", &self.password)?,
+            field("username", &self.username)?,
+            field("url", &self.url)?,
+        );
+        let custom = Zeroizing::new(field("custom", &self.custom)?);
+        entry.custom = serde_json::from_str(&custom).with_context(|| {
+            CandadoError::Corrupt(format!(
+                "Custom fields of entry '{}' are malformed",
+                self.id
+            ))
+        })?;
+        entry.kind = field("kind", &self.kind)?.parse()?;
+        Ok(entry)
     }
 }
 
@@ -67,34 +172,22 @@ pub trait Encrypt {
 
 impl Encrypt for Entry {
     fn encrypt(&self, encrypter: &Encrypter) -> Result<EncryptedEntry> {
-        Ok(EncryptedEntry::init(
-            encrypter.encrypt(&self.id)?,
-            encrypter.encrypt(&self.service)?,
-            encrypter.encrypt(&self.email)?,
-            encrypter.encrypt(&self.password)?,
-            encrypter.encrypt(&self.username)?,
-            encrypter.encrypt(&self.url)?,
-        ))
-    }
-}
-
-impl EncryptedEntry {
-    pub fn init(
-        id: Vec<u8>,
-        service: Vec<u8>,
-        email: Vec<u8>,
-        password: Vec<u8>,
-        username: Vec<u8>,
-        url: Vec<u8>,
-    ) -> Self {
-        Self {
-            id,
-            service,
-            email,
-            password,
-            username,
-            url,
-        }
+        let field = |name: &str, plain: &str| {
+            encrypter.encrypt_with(plain, &associated_data(&self.id, name))
+        };
+        Ok(EncryptedEntry {
+            id: self.id.clone(),
+            service: field("service", &self.service)?,
+            email: field("email", &self.email)?,
+            password: field("password", self.password.expose())?,
+            username: field("username", &self.username)?,
+            url: field("url", &self.url)?,
+            custom: field(
+                "custom",
+                &Zeroizing::new(serde_json::to_string(&self.custom)?),
+            )?,
+            kind: field("kind", self.kind.as_str())?,
+        })
     }
 }
 
@@ -111,16 +204,18 @@ impl Entry {
             id,
             service,
             email,
-            password,
+            password: SecretString::from(password),
             username,
             url,
+            custom: BTreeMap::new(),
+            kind: EntryKind::Login,
         }
     }
 
     pub fn new(
         service: String,
         email: String,
-        password: Option<String>,
+        password: Option<SecretString>,
         username: Option<String>,
         url: Option<String>,
     ) -> Self {
@@ -128,17 +223,22 @@ impl Entry {
             id: generators::gen_key(12),
             service,
             email,
-            password: password.unwrap_or(generators::gen_passphrase(4, &None)),
-            username: username.unwrap_or(String::new()),
-            url: url.unwrap_or(String::new()),
+            password: password
+                .unwrap_or_else(|| SecretString::from(generators::gen_passphrase(4, &None))),
+            username: username.unwrap_or_default(),
+            url: url.unwrap_or_default(),
+            custom: BTreeMap::new(),
+            kind: EntryKind::Login,
         }
     }
 
+    /// Replaces the fields that are `Some`. The old values are wiped, the
+    /// password by dropping its [`SecretString`].
     pub fn overite(
         &mut self,
         service: Option<String>,
         email: Option<String>,
-        password: Option<String>,
+        password: Option<SecretString>,
         username: Option<String>,
         url: Option<String>,
     ) {
@@ -146,6 +246,7 @@ impl Entry {
             ($self:ident, $($field:ident, $value:expr),*) => {
                 $(
                     if let Some(val) = $value {
+                        $self.$field.zeroize();
                         $self.$field = val;
                     }
                 )*
@@ -153,22 +254,86 @@ impl Entry {
         }
         update_if_some!(self, service, service);
         update_if_some!(self, email, email);
-        update_if_some!(self, password, password);
         update_if_some!(self, username, username);
         update_if_some!(self, url, url);
+        if let Some(password) = password {
+            self.password = password;
+        }
     }
 
-    pub const fn ref_array(&self) -> [&String; 6] {
+    pub fn ref_array(&self) -> [&str; 6] {
         [
             &self.id,
             &self.service,
             &self.email,
-            &self.password,
+            self.password.expose(),
             &self.username,
             &self.url,
         ]
     }
 
+    /// Names of the fields every entry has, in display order.
+    pub const FIELDS: [&'static str; 7] = [
+        "id", "service", "email", "password", "username", "url", "kind",
+    ];
+
+    /// The value of the field called `name`, one of [`Entry::FIELDS`] or a
+    /// custom field, which may also be written as `custom.<name>`.
+    pub fn field(&self, name: &str) -> Option<&str> {
+        match name {
+            "id" => Some(&self.id),
+            "service" => Some(&self.service),
+            "email" => Some(&self.email),
+            "password" => Some(self.password.expose()),
+            "username" => Some(&self.username),
+            "url" => Some(&self.url),
+            "kind" => Some(self.kind.as_str()),
+            _ => {
+                let name = name.strip_prefix("custom.").unwrap_or(name);
+                self.custom.get(name).map(SecretString::expose)
+            }
+        }
+    }
+
+    /// An entry holding the OpenSSH private key `private_key`, named
+    /// `service`. See [`EntryKind::SshKey`].
+    pub fn ssh_key(service: String, private_key: &str) -> Result<Self> {
+        let key = ssh_agent::parse_key(private_key)?;
+        let mut entry = Entry::new(
+            service,
+            String::new(),
+            Some(SecretString::from(private_key)),
+            Some(key.comment().to_owned()),
+            None,
+        );
+        entry.kind = EntryKind::SshKey;
+        Ok(entry)
+    }
+
+    /// Sets the custom field `name`, an empty `value` removes it.
+    pub fn set_custom(&mut self, name: &str, value: &str) -> Result<()> {
+        let name = name.strip_prefix("custom.").unwrap_or(name);
+        let valid = !name.is_empty()
+            && !Entry::FIELDS.contains(&name)
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !valid {
+            return Err(CandadoError::InvalidInput(format!(
+                "Invalid custom field name '{name}' -> Use letters, digits, '-', '_' and '.', other than {}.",
+                Entry::FIELDS.join(", ")
+            ))
+            .into());
+        }
+        match value.is_empty() {
+            true => self.custom.remove(name),
+            false => self
+                .custom
+                .insert(name.to_owned(), SecretString::from(value)),
+        };
+        Ok(())
+    }
+
     pub fn id(&self) -> &str {
         &self.id
     }
@@ -182,7 +347,7 @@ impl Entry {
     }
 
     pub fn password(&self) -> &str {
-        &self.password
+        self.password.expose()
     }
 
     pub fn username(&self) -> &str {
@@ -194,12 +359,14 @@ impl Entry {
     }
 }
 
+/// One line summary for searching. Leaves out the password and custom fields,
+/// those are only ever written through [`Entry::field`] or `expose`.
 impl fmt::Display for Entry {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{} | {} | {} | {} | {} | {}",
-            self.id, self.service, self.email, self.password, self.username, self.url
+            "{} | {} | {} | {} | {}",
+            self.id, self.service, self.email, self.username, self.url
         )
     }
 }
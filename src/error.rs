@@ -0,0 +1,52 @@
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// The failures callers of candadolib may want to tell apart.
+///
+/// Functions keep returning [`anyhow::Result`], the error inside is one of
+/// these whenever the failure is known. Use [`CandadoError::find`] to get it
+/// back, anything else is an unexpected error.
+#[derive(Debug, Error)]
+pub enum CandadoError {
+    /// The master, key file or recovery key is wrong or missing.
+    #[error("Authentication Failed -> {0}")]
+    AuthFailed(String),
+    /// There is no vault in the folder.
+    #[error("Keyfile not found in {} -> Use 'candado vault init' to initialize a new vault.", .0.display())]
+    NotInitialized(PathBuf),
+    /// No entry has the given id.
+    #[error("Entry '{0}' not found -> Use 'candado vault find' to look up its id.")]
+    NotFound(String),
+    /// More than one entry matches a query that has to pick one.
+    #[error(
+        "'{query}' matches {count} entries -> Use the id of one, 'candado vault find' lists them."
+    )]
+    Ambiguous { query: String, count: usize },
+    /// The entry exists but doesn't have the requested field.
+    #[error("Entry '{id}' has no field '{field}'")]
+    MissingField { id: String, field: String },
+    /// A ciphertext or the keyfile failed its integrity check or can't be
+    /// parsed.
+    #[error("{0}")]
+    Corrupt(String),
+    /// The vault has to be unlocked, but no agent holds the key and there is
+    /// no terminal to ask for the master.
+    #[error("The vault is locked and the master can't be asked for: {0} -> Start 'candado agent' or use --password-stdin.")]
+    Locked(String),
+    /// An argument or input file was rejected.
+    #[error("{0}")]
+    InvalidInput(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Database(#[from] rusqlite::Error),
+}
+
+impl CandadoError {
+    /// The candado error behind `err`, also if context was added to it.
+    pub fn find(err: &anyhow::Error) -> Option<&CandadoError> {
+        err.downcast_ref::<CandadoError>()
+            .or_else(|| err.chain().find_map(|cause| cause.downcast_ref()))
+    }
+}
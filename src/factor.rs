@@ -0,0 +1,59 @@
+use anyhow::{anyhow, Context, Result};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+use crate::error::CandadoError;
+use crate::secret::SecretBytes;
+
+/// Size of the random contents of a generated key file.
+const KEY_FILE_LEN: usize = 64;
+
+/// Reads a key file used as second factor.
+///
+/// Any file works, only a hash of its contents is mixed into the key
+/// derivation, so the file has to stay byte for byte the same.
+///
+/// A missing or empty key file fails authentication like a wrong master, any
+/// other read error is reported as I/O error.
+pub fn load_key_file(path: &Path) -> Result<SecretBytes> {
+    let contents = match fs::read(path) {
+        Ok(contents) => Zeroizing::new(contents),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(CandadoError::AuthFailed(format!(
+                "Key file '{}' not found -> Check the --keyfile path or insert the drive holding it.",
+                path.display()
+            ))
+            .into())
+        }
+        Err(e) => {
+            return Err(CandadoError::Io(e))
+                .with_context(|| format!("Can't read key file '{}'", path.display()))
+        }
+    };
+    if contents.is_empty() {
+        return Err(
+            CandadoError::AuthFailed(format!("Key file '{}' is empty", path.display())).into(),
+        );
+    }
+    Ok(SecretBytes::from(&Sha256::digest(&*contents)[..]))
+}
+
+/// Writes a new key file with random contents. Fails if `path` exists.
+pub fn create_key_file(path: &Path) -> Result<()> {
+    let mut contents = Zeroizing::new([0u8; KEY_FILE_LEN]);
+    OsRng.fill_bytes(contents.as_mut());
+    let mut file = File::options()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| anyhow!("Can't create key file '{}': {e}", path.display()))?;
+    file.write_all(contents.as_ref())?;
+    file.sync_all()?;
+    Ok(())
+}
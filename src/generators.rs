@@ -1,8 +1,13 @@
+use anyhow::{anyhow, Result};
+use core::fmt;
 use std::path::PathBuf;
+use std::str::FromStr;
 
-use rand::{distributions::Alphanumeric, prelude::*};
+use rand::{distributions::Alphanumeric, prelude::*, rngs::OsRng};
+use ssh_key::{Algorithm, LineEnding, PrivateKey};
+use zeroize::Zeroizing;
 
-const WORD_LIST: &str = include_str!("wordlist.txt");
+pub(crate) const WORD_LIST: &str = include_str!("wordlist.txt");
 
 pub fn gen_password(length: u32) -> String {
     const CHARS: &str = concat!(
@@ -26,7 +31,8 @@ pub fn gen_password(length: u32) -> String {
 
         if password.chars().any(|c| c.is_lowercase())
             && password.chars().any(|c| c.is_uppercase())
-            && (password.chars().filter(|c| c.is_digit(10)).count() as f64 / length as f64) * 100.0
+            && (password.chars().filter(|c| c.is_ascii_digit()).count() as f64 / length as f64)
+                * 100.0
                 >= 20.0
         {
             break;
@@ -68,3 +74,56 @@ pub fn gen_passphrase(length: u32, wordlist: &Option<PathBuf>) -> String {
         .collect::<Vec<&str>>()
         .join(" ")
 }
+
+/// Key types `candado gen ssh-key` creates, the ones the SSH agent serves.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SshKeyType {
+    #[default]
+    Ed25519,
+    /// 4096 bit rsa, for servers that don't take ed25519 yet.
+    Rsa,
+}
+
+impl SshKeyType {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            SshKeyType::Ed25519 => "ed25519",
+            SshKeyType::Rsa => "rsa",
+        }
+    }
+}
+
+impl FromStr for SshKeyType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ed25519" => Ok(SshKeyType::Ed25519),
+            "rsa" => Ok(SshKeyType::Rsa),
+            _ => Err(anyhow!(
+                "Unsupported SSH key type '{s}', expected ed25519 or rsa"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SshKeyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A new unencrypted key pair in OpenSSH format, the private key and the
+/// `authorized_keys` line of its public key.
+pub fn gen_ssh_key(key_type: SshKeyType, comment: &str) -> Result<(Zeroizing<String>, String)> {
+    let algorithm = match key_type {
+        SshKeyType::Ed25519 => Algorithm::Ed25519,
+        SshKeyType::Rsa => Algorithm::Rsa { hash: None },
+    };
+    let mut key = PrivateKey::random(&mut OsRng, algorithm)?;
+    key.set_comment(comment);
+    Ok((
+        key.to_openssh(LineEnding::LF)?,
+        key.public_key().to_openssh()?,
+    ))
+}
@@ -0,0 +1,215 @@
+use anyhow::Result;
+use std::io::{BufRead, Write};
+use zeroize::Zeroizing;
+
+use crate::{CandadoError, Entry, SecretString, Vault};
+
+/// One credential as exchanged with git on stdin and stdout, see
+/// `git help credential`.
+///
+/// Every key is optional. `url` is split into the other keys when read, so
+/// only `protocol`, `host` and `path` are matched against entries.
+#[derive(Default)]
+pub struct GitCredential {
+    pub protocol: Option<String>,
+    pub host: Option<String>,
+    pub path: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<SecretString>,
+}
+
+impl GitCredential {
+    /// Reads `key=value` lines up to a blank line or the end of `input`.
+    /// Keys candado doesn't use are skipped.
+    pub fn read(input: impl BufRead) -> Result<Self> {
+        let mut credential = GitCredential::default();
+        for line in input.lines() {
+            let line = Zeroizing::new(line?);
+            if line.is_empty() {
+                break;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(CandadoError::InvalidInput(
+                    "Invalid credential from git -> Expected key=value lines.".to_owned(),
+                )
+                .into());
+            };
+            let value = value.to_owned();
+            match key {
+                "protocol" => credential.protocol = Some(value),
+                "host" => credential.host = Some(value),
+                "path" => credential.path = Some(value),
+                "username" => credential.username = Some(value),
+                "password" => credential.password = Some(SecretString::from(value)),
+                "url" => {
+                    let url = Url::parse(&value);
+                    credential.protocol = url.protocol.map(str::to_owned);
+                    credential.host = Some(url.host.to_owned());
+                    credential.path = url.path.map(str::to_owned);
+                }
+                _ => {}
+            }
+        }
+        Ok(credential)
+    }
+
+    /// Writes the username and password of `entry` for git.
+    pub fn write(entry: &Entry, mut output: impl Write) -> Result<()> {
+        let mut out = Zeroizing::new(String::new());
+        if !entry.username.is_empty() {
+            out.push_str(&format!("username={}\n", entry.username));
+        }
+        out.push_str(&format!("password={}\n", entry.password.expose()));
+        output.write_all(out.as_bytes())?;
+        output.flush()?;
+        Ok(())
+    }
+
+    /// How well `entry` fits this credential, `None` if it doesn't.
+    ///
+    /// Protocol and host have to be the same. An entry url with a path only
+    /// fits the same path, and is preferred over one without when git sends
+    /// the path too.
+    fn rank(&self, entry: &Entry) -> Option<u8> {
+        if entry.url.is_empty() {
+            return None;
+        }
+        let url = Url::parse(&entry.url);
+        let host = self.host.as_deref()?;
+        if !url.host.eq_ignore_ascii_case(host) {
+            return None;
+        }
+        if let (Some(protocol), Some(wanted)) = (url.protocol, &self.protocol) {
+            if !protocol.eq_ignore_ascii_case(wanted) {
+                return None;
+            }
+        }
+        if let Some(username) = self.username.as_deref() {
+            if !entry.username.is_empty() && entry.username != username {
+                return None;
+            }
+        }
+        match (url.path.map(trim_path), self.path.as_deref().map(trim_path)) {
+            (None, _) => Some(1),
+            (Some(path), Some(wanted)) if path == wanted => Some(2),
+            (Some(_), Some(_)) => None,
+            (Some(_), None) => Some(0),
+        }
+    }
+
+    /// The url of an entry stored for this credential.
+    fn url(&self) -> String {
+        let mut url = format!(
+            "{}://{}",
+            self.protocol.as_deref().unwrap_or("https"),
+            self.host.as_deref().unwrap_or_default()
+        );
+        if let Some(path) = self.path.as_deref().filter(|path| !path.is_empty()) {
+            url.push('/');
+            url.push_str(path.trim_start_matches('/'));
+        }
+        url
+    }
+
+    /// The entry that fits this credential best, see [`GitCredential::rank`].
+    pub fn find(&self, vault: &Vault) -> Result<Option<Entry>> {
+        self.best(vault, |_| true)
+    }
+
+    /// Like [`GitCredential::find`], but only among the entries `keep` accepts.
+    fn best(&self, vault: &Vault, keep: impl Fn(&Entry) -> bool) -> Result<Option<Entry>> {
+        let mut best: Option<(u8, Entry)> = None;
+        for entry in vault.list()?.into_iter().filter(|entry| keep(entry)) {
+            if let Some(rank) = self.rank(&entry) {
+                if best.as_ref().is_none_or(|(best, _)| rank > *best) {
+                    best = Some((rank, entry));
+                }
+            }
+        }
+        Ok(best.map(|(_, entry)| entry))
+    }
+
+    /// Saves the password git reports as working. Updates the entry
+    /// [`GitCredential::find`] returns for this credential, or adds a new one
+    /// named after the host if none fits.
+    ///
+    /// When git sends a username only an entry with exactly that username is
+    /// updated. An entry without one, like the web login of the same host,
+    /// fits any user for `get` but is never overwritten with their password.
+    pub fn store(&self, vault: &Vault) -> Result<()> {
+        let (Some(host), Some(password)) = (&self.host, &self.password) else {
+            return Err(CandadoError::InvalidInput(
+                "git sent no host or password to store".to_owned(),
+            )
+            .into());
+        };
+        let url = self.url();
+        let username = self.username.clone().unwrap_or_default();
+        let password = password.clone();
+        let existing = match self.username.as_deref() {
+            Some(username) => self.best(vault, |entry| entry.username == username)?,
+            None => self.find(vault)?,
+        };
+        match existing {
+            Some(entry) => vault.update(&entry.id, None, None, Some(password), None, None, &[]),
+            None => vault.add(Entry::new(
+                host.clone(),
+                String::new(),
+                Some(password),
+                Some(username),
+                Some(url),
+            )),
+        }
+    }
+
+    /// Removes the entries git reports as rejected. Only entries still holding
+    /// the rejected password are removed, so a password changed in the meantime
+    /// is kept.
+    pub fn erase(&self, vault: &Vault) -> Result<()> {
+        let Some(password) = &self.password else {
+            return Ok(());
+        };
+        for entry in vault.list()? {
+            if self.rank(&entry).is_some() && entry.password == *password {
+                vault.remove(&entry.id)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The parts of an entry url git credentials are matched on.
+struct Url<'a> {
+    protocol: Option<&'a str>,
+    host: &'a str,
+    path: Option<&'a str>,
+}
+
+impl<'a> Url<'a> {
+    /// Splits `protocol://user@host:port/path`, everything but the host is
+    /// optional. The port stays part of the host, as git sends it.
+    fn parse(url: &'a str) -> Self {
+        let (protocol, rest) = match url.split_once("://") {
+            Some((protocol, rest)) => (Some(protocol), rest),
+            None => (None, url),
+        };
+        let (authority, path) = match rest.split_once('/') {
+            Some((authority, path)) => (authority, Some(path).filter(|p| !p.is_empty())),
+            None => (rest, None),
+        };
+        let host = authority
+            .rsplit_once('@')
+            .map_or(authority, |(_, host)| host);
+        Url {
+            protocol,
+            host,
+            path,
+        }
+    }
+}
+
+/// Compares paths without surrounding slashes or a `.git` suffix.
+fn trim_path(path: &str) -> &str {
+    let path = path.trim_matches('/');
+    path.strip_suffix(".git").unwrap_or(path)
+}
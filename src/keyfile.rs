@@ -0,0 +1,436 @@
+use anyhow::{anyhow, Result};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use argon2::password_hash::PasswordHash;
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, set_permissions, DirBuilder, File, Permissions};
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::cipher::CipherSuite;
+use crate::error::CandadoError;
+
+/// Version written by this release. Keyfiles without a version are the
+/// original base64 `salt hash ekey` blobs and count as version 1, version 2
+/// is the first JSON format and version 3 adds the key file flag, which
+/// older releases would silently ignore.
+pub const KEYFILE_VERSION: u32 = 3;
+
+/// Oldest JSON keyfile version this release reads.
+const MIN_JSON_VERSION: u32 = 2;
+
+pub const KEYFILE_NAME: &str = ".candado.key";
+
+/// Infix of the keyfiles staged by a key rotation, followed by a random id so
+/// no other keyfile write ever touches them.
+const ROTATION_INFIX: &str = ".rotate-";
+
+const CORRUPT_KEYFILE: &str = "Keyfile is corrupt or in an unknown format";
+
+/// Cost settings for Argon2. Memory is given in KiB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfCost {
+    pub memory: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfCost {
+    fn default() -> Self {
+        Self {
+            memory: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl KdfCost {
+    /// Most iterations [`KdfCost::calibrate`] picks. Far beyond any sane
+    /// unlock time with the smallest memory, and small enough to be passed
+    /// back as `--iterations`.
+    pub const MAX_ITERATIONS: u32 = 1 << 20;
+
+    pub fn validate(&self) -> Result<()> {
+        Params::new(self.memory, self.iterations, self.parallelism, Some(32))
+            .map_err(|e| anyhow!("Invalid kdf parameters: {e}"))?;
+        Ok(())
+    }
+
+    /// Finds the number of iterations that makes a single derivation with the
+    /// given memory and parallelism take roughly `target` on this machine.
+    ///
+    /// Returns the calibrated cost and the time the final run took.
+    pub fn calibrate(target: Duration, memory: u32, parallelism: u32) -> Result<(Self, Duration)> {
+        let mut cost = Self {
+            memory,
+            iterations: 1,
+            parallelism,
+        };
+        cost.validate()?;
+
+        // Scale the iterations by how far off the last run was, a few rounds
+        // are enough since the cost grows linearly with the iterations.
+        let mut elapsed = cost.measure()?;
+        for _ in 0..4 {
+            let elapsed_ns = elapsed.as_nanos().max(1);
+            let iterations = u128::from(cost.iterations)
+                .checked_mul(target.as_nanos())
+                .map(|scaled| (scaled + elapsed_ns / 2) / elapsed_ns)
+                .and_then(|scaled| u32::try_from(scaled).ok())
+                .filter(|iterations| *iterations <= Self::MAX_ITERATIONS)
+                .ok_or_else(|| {
+                    CandadoError::InvalidInput(format!(
+                        "Reaching the target takes more than {} iterations -> Use a shorter target or more memory.",
+                        Self::MAX_ITERATIONS
+                    ))
+                })?
+                .max(1);
+            if iterations == cost.iterations {
+                break;
+            }
+            cost.iterations = iterations;
+            elapsed = cost.measure()?;
+        }
+        Ok((cost, elapsed))
+    }
+
+    fn measure(&self) -> Result<Duration> {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let argon2 = KdfParams::new(&salt, self).argon2()?;
+        let mut out = [0u8; 32];
+        let start = Instant::now();
+        argon2
+            .hash_password_into(b"candado-kdf-bench", &salt, &mut out)
+            .map_err(|e| anyhow!("Error deriving password: {e}"))?;
+        Ok(start.elapsed())
+    }
+}
+
+/// Key derivation settings stored alongside the vault, so the cost can be
+/// raised for new vaults without locking out existing ones.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct KdfParams {
+    pub algorithm: String,
+    pub version: u32,
+    pub memory: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+    pub salt: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Keyfile {
+    pub version: u32,
+    pub cipher: String,
+    pub kdf: KdfParams,
+    pub hash: String,
+    pub key: String,
+    /// The data key wrapped with the recovery key, if the vault has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recovery: Option<String>,
+    /// The data key wrapped with the secret split by `vault split`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shares: Option<String>,
+    /// Whether a key file is mixed into the derivation next to the master.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub key_file: bool,
+}
+
+impl KdfParams {
+    pub fn new(salt: &[u8], cost: &KdfCost) -> Self {
+        Self {
+            algorithm: Algorithm::Argon2id.as_str().to_owned(),
+            version: Version::V0x13 as u32,
+            memory: cost.memory,
+            iterations: cost.iterations,
+            parallelism: cost.parallelism,
+            salt: STANDARD.encode(salt),
+        }
+    }
+
+    /// Returns the same settings with a new random salt.
+    pub fn resalt(&self) -> Self {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        Self {
+            salt: STANDARD.encode(salt),
+            ..self.clone()
+        }
+    }
+
+    pub fn salt(&self) -> Result<Vec<u8>> {
+        Ok(STANDARD.decode(&self.salt)?)
+    }
+
+    pub fn argon2(&self) -> Result<Argon2<'static>> {
+        let (algorithm, version, params) = self.settings()?;
+        Ok(Argon2::new(algorithm, version, params))
+    }
+
+    /// Like [`KdfParams::argon2`], but every derivation also depends on `secret`.
+    pub fn argon2_with_secret<'k>(&self, secret: &'k [u8]) -> Result<Argon2<'k>> {
+        let (algorithm, version, params) = self.settings()?;
+        Argon2::new_with_secret(secret, algorithm, version, params)
+            .map_err(|e| anyhow!("Invalid kdf secret: {e}"))
+    }
+
+    fn settings(&self) -> Result<(Algorithm, Version, Params)> {
+        let algorithm = Algorithm::new(&self.algorithm)
+            .map_err(|e| anyhow!("Unsupported kdf algorithm '{}': {e}", self.algorithm))?;
+        let version = Version::try_from(self.version)
+            .map_err(|e| anyhow!("Unsupported kdf version '{}': {e}", self.version))?;
+        let params = Params::new(self.memory, self.iterations, self.parallelism, Some(32))
+            .map_err(|e| anyhow!("Invalid kdf parameters: {e}"))?;
+        Ok((algorithm, version, params))
+    }
+}
+
+impl Keyfile {
+    /// Starts a keyfile for a new vault. The hash and wrapped data key are
+    /// filled in once a master is set.
+    pub fn new(cipher: CipherSuite, kdf: KdfParams) -> Self {
+        Self {
+            version: KEYFILE_VERSION,
+            cipher: cipher.as_str().to_owned(),
+            kdf,
+            hash: String::new(),
+            key: String::new(),
+            recovery: None,
+            shares: None,
+            key_file: false,
+        }
+    }
+
+    /// Reads a keyfile in any known format. Legacy keyfiles are converted in
+    /// memory and only rewritten once the vault is unlocked successfully.
+    pub fn load(path: &Path) -> Result<Self> {
+        let raw = fs::read(path).map_err(CandadoError::Io)?;
+        match serde_json::from_slice::<Keyfile>(&raw) {
+            Ok(keyfile) if keyfile.version > KEYFILE_VERSION => Err(CandadoError::Corrupt(format!(
+                "Keyfile version {} is newer than this release supports -> Upgrade candado to open this vault.",
+                keyfile.version
+            ))
+            .into()),
+            Ok(keyfile) if keyfile.version < MIN_JSON_VERSION => Err(CandadoError::Corrupt(
+                format!("Unknown keyfile version {}", keyfile.version),
+            )
+            .into()),
+            Ok(keyfile) => Ok(keyfile),
+            Err(_) => Keyfile::parse_legacy(&raw),
+        }
+    }
+
+    fn parse_legacy(raw: &[u8]) -> Result<Self> {
+        let decoded = STANDARD
+            .decode(raw)
+            .map_err(|_| CandadoError::Corrupt(CORRUPT_KEYFILE.to_owned()))?;
+        let content = String::from_utf8_lossy(&decoded).to_string();
+        let keys: Vec<&str> = content.splitn(3, ' ').collect();
+        if keys.len() != 3 {
+            return Err(CandadoError::Corrupt(CORRUPT_KEYFILE.to_owned()).into());
+        }
+        let salt = STANDARD
+            .decode(keys[0])
+            .map_err(|_| CandadoError::Corrupt(CORRUPT_KEYFILE.to_owned()))?;
+        Ok(Self {
+            version: 1,
+            cipher: CipherSuite::Aes256Gcm.as_str().to_owned(),
+            // Every vault used Argon2id with the crate defaults before the
+            // parameters were recorded.
+            kdf: KdfParams::new(&salt, &KdfCost::default()),
+            hash: keys[1].to_owned(),
+            key: keys[2].to_owned(),
+            recovery: None,
+            shares: None,
+            key_file: false,
+        })
+    }
+
+    /// Inspects the keyfile at `path` without unlocking it and returns every
+    /// problem found. An empty list means the keyfile looks healthy.
+    pub fn check(path: &Path) -> Vec<String> {
+        let mut issues = vec![];
+
+        if let Some(dir_path) = path.parent() {
+            match fs::metadata(dir_path) {
+                Ok(meta) if meta.permissions().mode() & 0o077 != 0 => issues.push(format!(
+                    "Vault folder permissions are {:o}, expected 700",
+                    meta.permissions().mode() & 0o777
+                )),
+                Ok(_) => {}
+                Err(e) => issues.push(format!("Can't read vault folder: {e}")),
+            }
+            if Keyfile::staged_path(path).exists() {
+                issues.push("Found a staged keyfile left by an interrupted write".to_owned());
+            }
+            match Keyfile::rotations(path) {
+                Ok(rotations) if !rotations.is_empty() => issues.push(format!(
+                    "Found {} staged keyfile(s) left by an interrupted key rotation, the next unlock finishes or drops them",
+                    rotations.len()
+                )),
+                Ok(_) => {}
+                Err(e) => issues.push(format!("Can't read vault folder: {e}")),
+            }
+        }
+
+        match fs::metadata(path) {
+            Ok(meta) if meta.permissions().mode() & 0o077 != 0 => issues.push(format!(
+                "Keyfile permissions are {:o}, expected 600",
+                meta.permissions().mode() & 0o777
+            )),
+            Ok(_) => {}
+            Err(e) => {
+                issues.push(format!("Can't read keyfile: {e}"));
+                return issues;
+            }
+        }
+
+        let keyfile = match Keyfile::load(path) {
+            Ok(keyfile) => keyfile,
+            Err(e) => {
+                issues.push(format!("{e}"));
+                return issues;
+            }
+        };
+        if !keyfile.is_current() {
+            issues.push(format!(
+                "Keyfile uses format version {}, it is upgraded on the next unlock",
+                keyfile.version
+            ));
+        }
+        if let Err(e) = keyfile.cipher.parse::<CipherSuite>() {
+            issues.push(format!("{e}"));
+        }
+        if let Err(e) = keyfile.kdf.argon2() {
+            issues.push(format!("{e}"));
+        }
+        match keyfile.kdf.salt() {
+            Ok(salt) if salt.len() < 8 => issues.push("Keyfile salt is too short".to_owned()),
+            Ok(_) => {}
+            Err(e) => issues.push(format!("Keyfile salt is not valid base64: {e}")),
+        }
+        if let Err(e) = PasswordHash::new(&keyfile.hash) {
+            issues.push(format!("Keyfile hash is malformed: {e}"));
+        }
+        if !Keyfile::is_wrapped_key(&keyfile.key) {
+            issues.push("Keyfile data key is malformed".to_owned());
+        }
+        if let Some(recovery) = &keyfile.recovery {
+            if !Keyfile::is_wrapped_key(recovery) {
+                issues.push("Keyfile recovery key slot is malformed".to_owned());
+            }
+        }
+        if let Some(shares) = &keyfile.shares {
+            if !Keyfile::is_wrapped_key(shares) {
+                issues.push("Keyfile recovery shares slot is malformed".to_owned());
+            }
+        }
+        issues
+    }
+
+    fn is_wrapped_key(key: &str) -> bool {
+        match key.split_once(':') {
+            Some((nonce, key)) => STANDARD.decode(nonce).is_ok() && STANDARD.decode(key).is_ok(),
+            None => false,
+        }
+    }
+
+    pub fn is_current(&self) -> bool {
+        self.version == KEYFILE_VERSION
+    }
+
+    /// Where [`Keyfile::stage`] writes the keyfile replacing the one at
+    /// `keypath`.
+    pub fn staged_path(keypath: &Path) -> PathBuf {
+        keypath.with_file_name(format!("{KEYFILE_NAME}.tmp"))
+    }
+
+    /// Where [`Keyfile::stage_rotation`] writes the keyfile of a new rotation
+    /// replacing the one at `keypath`.
+    pub fn rotation_path(keypath: &Path) -> PathBuf {
+        keypath.with_file_name(format!(
+            "{KEYFILE_NAME}{ROTATION_INFIX}{:016x}",
+            OsRng.next_u64()
+        ))
+    }
+
+    /// The keyfiles staged by rotations next to the keyfile at `keypath`.
+    pub fn rotations(keypath: &Path) -> Result<Vec<PathBuf>> {
+        let Some(dir_path) = keypath.parent() else {
+            return Ok(vec![]);
+        };
+        let prefix = format!("{KEYFILE_NAME}{ROTATION_INFIX}");
+        let dir = match fs::read_dir(dir_path) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        let mut rotations = vec![];
+        for entry in dir {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with(&prefix) {
+                rotations.push(entry.path());
+            }
+        }
+        Ok(rotations)
+    }
+
+    /// Writes the keyfile next to the one at `keypath` without replacing it
+    /// yet. Returns the path of the staged file.
+    pub fn stage(&self, keypath: &Path) -> Result<PathBuf> {
+        self.stage_at(&Keyfile::staged_path(keypath))
+    }
+
+    /// Like [`Keyfile::stage`], but under a path of its own that only this
+    /// rotation uses.
+    pub fn stage_rotation(&self, keypath: &Path) -> Result<PathBuf> {
+        self.stage_at(&Keyfile::rotation_path(keypath))
+    }
+
+    fn stage_at(&self, tmppath: &Path) -> Result<PathBuf> {
+        let dir_path = tmppath
+            .parent()
+            .ok_or_else(|| anyhow!("Invalid keyfile path"))?;
+        if !dir_path.exists() {
+            DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(dir_path)?;
+            set_permissions(dir_path, Permissions::from_mode(0o700))?;
+        }
+
+        // The keyfile is written to a temporary file first and renamed over
+        // the old one, so a crash midway never leaves a vault without a key.
+        let mut keyfile = File::options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(tmppath)?;
+        keyfile.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        keyfile.sync_all()?;
+        set_permissions(tmppath, Permissions::from_mode(0o600))?;
+        Ok(tmppath.to_owned())
+    }
+
+    /// Atomically replaces the keyfile with a previously staged one.
+    pub fn commit(staged: &Path) -> Result<()> {
+        let dir_path = staged
+            .parent()
+            .ok_or_else(|| anyhow!("Invalid keyfile path"))?;
+        fs::rename(staged, dir_path.join(KEYFILE_NAME))?;
+        File::open(dir_path)?.sync_all()?;
+        Ok(())
+    }
+
+    pub fn write(&self, keypath: &Path) -> Result<()> {
+        let staged = self.stage(keypath)?;
+        Keyfile::commit(&staged)
+    }
+}
@@ -0,0 +1,185 @@
+use anyhow::{anyhow, Result};
+use core::fmt;
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use std::str::FromStr;
+use zeroize::Zeroizing;
+
+use crate::{error::CandadoError, Entry, SecretString};
+
+/// Shown instead of the password unless it is revealed. Always the same
+/// length, so the output doesn't leak how long a password is.
+pub const HIDDEN: &str = "********";
+
+/// Ways entries are written for other programs instead of the TUI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// A JSON array of entry objects.
+    Json,
+    /// One entry per line, fields separated by tabs.
+    Tsv,
+    /// `field: value` lines, entries separated by a blank line. A single field
+    /// is printed bare, one value per line.
+    Plain,
+}
+
+impl Format {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Tsv => "tsv",
+            Format::Plain => "plain",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(Format::Json),
+            "tsv" => Ok(Format::Tsv),
+            "plain" => Ok(Format::Plain),
+            _ => Err(anyhow!(
+                "Unsupported format '{s}', expected json, tsv or plain"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Writes `entries` in `format`, limited to `fields` in that order or all of
+/// [`Entry::FIELDS`] if empty. Passwords and custom fields are replaced by
+/// [`HIDDEN`] unless `reveal` is set.
+pub fn render(
+    entries: &[Entry],
+    format: Format,
+    fields: &[String],
+    reveal: bool,
+) -> Result<Zeroizing<String>> {
+    let all = fields.is_empty();
+    let fields: Vec<&str> = match all {
+        true => Entry::FIELDS.to_vec(),
+        false => fields.iter().map(String::as_str).collect(),
+    };
+    // Without entries there is nothing to check custom fields against
+    let known = |field: &&str| {
+        Entry::FIELDS.contains(field)
+            || entries.is_empty()
+            || entries.iter().any(|entry| entry.field(field).is_some())
+    };
+    if let Some(unknown) = fields.iter().find(|field| !known(field)) {
+        return Err(CandadoError::InvalidInput(format!(
+            "Unknown field '{unknown}' -> Use one of {} or a custom field.",
+            Entry::FIELDS.join(", ")
+        ))
+        .into());
+    }
+
+    let entries: Vec<Entry> = entries
+        .iter()
+        .map(|entry| {
+            let mut entry = entry.clone();
+            if !reveal {
+                entry.password = SecretString::from(HIDDEN);
+                for value in entry.custom.values_mut() {
+                    *value = SecretString::from(HIDDEN);
+                }
+            }
+            entry
+        })
+        .collect();
+
+    let mut out = Zeroizing::new(String::new());
+    match format {
+        Format::Json => {
+            // Serialized straight from the entries, so no copy of the secrets
+            // is left in an intermediate value
+            let mut json = Zeroizing::new(Vec::new());
+            match all {
+                true => serde_json::to_writer_pretty(&mut *json, &entries)?,
+                false => {
+                    let selected: Vec<Selected> = entries
+                        .iter()
+                        .map(|entry| Selected {
+                            entry,
+                            fields: &fields,
+                        })
+                        .collect();
+                    serde_json::to_writer_pretty(&mut *json, &selected)?
+                }
+            }
+            json.push(b'\n');
+            out = Zeroizing::new(String::from_utf8(std::mem::take(&mut *json))?);
+        }
+        // Values are written straight into `out`, a temporary `String` would
+        // leave revealed secrets behind in freed memory
+        Format::Tsv => {
+            for entry in &entries {
+                for (i, field) in fields.iter().enumerate() {
+                    if i > 0 {
+                        out.push('\t');
+                    }
+                    push_tsv(&mut out, entry.field(field).unwrap_or_default());
+                }
+                out.push('\n');
+            }
+        }
+        Format::Plain if fields.len() == 1 => {
+            for entry in &entries {
+                out.push_str(entry.field(fields[0]).unwrap_or_default());
+                out.push('\n');
+            }
+        }
+        Format::Plain => {
+            for (i, entry) in entries.iter().enumerate() {
+                if i > 0 {
+                    out.push('\n');
+                }
+                for field in &fields {
+                    out.push_str(field);
+                    out.push_str(": ");
+                    out.push_str(entry.field(field).unwrap_or_default());
+                    out.push('\n');
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// An entry limited to some fields, written as an object with the fields in
+/// the requested order.
+struct Selected<'a> {
+    entry: &'a Entry,
+    fields: &'a [&'a str],
+}
+
+impl Serialize for Selected<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.fields.len()))?;
+        for field in self.fields {
+            map.serialize_entry(field, &self.entry.field(field))?;
+        }
+        map.end()
+    }
+}
+
+/// Appends `value` to `out` on one line, tabs and newlines inside it are
+/// written as `\t` and `\n`.
+fn push_tsv(out: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c),
+        }
+    }
+}
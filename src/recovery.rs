@@ -0,0 +1,171 @@
+use anyhow::{anyhow, Result};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use zeroize::Zeroizing;
+
+use crate::generators::WORD_LIST;
+use crate::secret::{SecretBytes, SecretString};
+use crate::shamir;
+
+/// Size of the secret behind a recovery key, the same as the data key.
+const RECOVERY_KEY_LEN: usize = 32;
+
+/// Size of a share: the threshold it was split with, its x coordinate and one
+/// byte per byte of the secret.
+const SHARE_LEN: usize = RECOVERY_KEY_LEN + 2;
+
+/// A random secret that unwraps the data key without the master.
+///
+/// It is only ever shown to the user as words from the bundled word list,
+/// the keyfile holds nothing but the data key wrapped with it.
+pub struct RecoveryKey {
+    secret: SecretBytes,
+}
+
+impl RecoveryKey {
+    pub fn generate() -> Self {
+        let mut secret = Zeroizing::new([0u8; RECOVERY_KEY_LEN]);
+        OsRng.fill_bytes(secret.as_mut());
+        Self {
+            secret: SecretBytes::from(&secret[..]),
+        }
+    }
+
+    /// Reads a recovery key back from the words it was shown as.
+    pub fn parse(phrase: &str) -> Result<Self> {
+        Ok(Self {
+            secret: decode_words(phrase, RECOVERY_KEY_LEN)?,
+        })
+    }
+
+    pub fn phrase(&self) -> SecretString {
+        encode_words(self.secret.expose())
+    }
+
+    /// Splits the key into `shares` phrases, any `threshold` of which restore
+    /// it with [`RecoveryKey::combine`]. Fewer shares reveal nothing about it.
+    pub fn split(&self, shares: u8, threshold: u8) -> Result<Vec<SecretString>> {
+        if threshold < 2 || threshold > shares {
+            return Err(anyhow!(
+                "Threshold must be at least 2 and at most the number of shares."
+            ));
+        }
+        Ok(shamir::split(self.secret.expose(), threshold, shares)
+            .iter()
+            .map(|share| {
+                let mut bytes = Zeroizing::new(vec![threshold]);
+                bytes.extend_from_slice(share);
+                encode_words(&bytes)
+            })
+            .collect())
+    }
+
+    /// Returns how many shares are needed to combine the split `share` is from.
+    pub fn threshold(share: &str) -> Result<u8> {
+        let bytes = decode_words(share, SHARE_LEN)?;
+        match bytes.expose()[0] {
+            threshold if threshold >= 2 => Ok(threshold),
+            _ => Err(anyhow!("The share is not valid.")),
+        }
+    }
+
+    /// Restores a key from shares made by [`RecoveryKey::split`].
+    pub fn combine(shares: &[SecretString]) -> Result<Self> {
+        let threshold = match shares.first() {
+            Some(share) => RecoveryKey::threshold(share.expose())?,
+            None => return Err(anyhow!("No shares given.")),
+        };
+
+        let mut decoded = vec![];
+        for share in shares {
+            let bytes = decode_words(share.expose(), SHARE_LEN)?;
+            if bytes.expose()[0] != threshold {
+                return Err(anyhow!("The shares come from different splits."));
+            }
+            decoded.push(bytes);
+        }
+
+        let parts: Vec<&[u8]> = decoded.iter().map(|bytes| &bytes.expose()[1..]).collect();
+        let secret = shamir::combine(&parts, threshold)
+            .map_err(|e| anyhow!("{e} -> Enter {threshold} different shares."))?;
+        Ok(Self {
+            secret: SecretBytes::from(&secret[..]),
+        })
+    }
+
+    pub(crate) fn secret(&self) -> &SecretBytes {
+        &self.secret
+    }
+}
+
+fn words() -> Vec<&'static str> {
+    WORD_LIST.lines().collect()
+}
+
+/// Number of words needed to spell out `len` bytes.
+fn word_count(len: usize, words: usize) -> usize {
+    ((len * 8) as f64 / (words as f64).log2()).ceil() as usize
+}
+
+/// Spells out `bytes` as a big endian number in base `words.len()`, so the
+/// words map back to exactly the same bytes.
+pub(crate) fn encode_words(bytes: &[u8]) -> SecretString {
+    let words = words();
+    let base = words.len() as u32;
+    let mut number = Zeroizing::new(bytes.to_vec());
+    let mut digits = Zeroizing::new(vec![0usize; word_count(bytes.len(), words.len())]);
+
+    for digit in digits.iter_mut().rev() {
+        let mut remainder = 0u32;
+        for byte in number.iter_mut() {
+            let acc = (remainder << 8) | *byte as u32;
+            *byte = (acc / base) as u8;
+            remainder = acc % base;
+        }
+        *digit = remainder as usize;
+    }
+
+    SecretString::new(
+        digits
+            .iter()
+            .map(|&digit| words[digit])
+            .collect::<Vec<&str>>()
+            .join(" "),
+    )
+}
+
+/// Reverses [`encode_words`] into exactly `len` bytes.
+pub(crate) fn decode_words(phrase: &str, len: usize) -> Result<SecretBytes> {
+    let words = words();
+    let base = words.len() as u32;
+    let given: Zeroizing<Vec<String>> = Zeroizing::new(
+        phrase
+            .split_whitespace()
+            .map(|word| word.to_lowercase())
+            .collect(),
+    );
+    let expected = word_count(len, words.len());
+    if given.len() != expected {
+        return Err(anyhow!(
+            "Expected {expected} words but got {} -> Check the recovery words.",
+            given.len()
+        ));
+    }
+
+    let mut number = Zeroizing::new(vec![0u8; len]);
+    for word in given.iter() {
+        let digit = words
+            .iter()
+            .position(|w| w == word)
+            .ok_or_else(|| anyhow!("Unknown word '{word}' -> Check the recovery words."))?;
+        let mut carry = digit as u32;
+        for byte in number.iter_mut().rev() {
+            let acc = *byte as u32 * base + carry;
+            *byte = acc as u8;
+            carry = acc >> 8;
+        }
+        if carry != 0 {
+            return Err(anyhow!("The recovery words are not valid."));
+        }
+    }
+    Ok(SecretBytes::from(&number[..]))
+}
@@ -0,0 +1,242 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::sync::{Mutex, OnceLock};
+use zeroize::Zeroize;
+
+/// Number of live secrets on each locked page, by page address.
+///
+/// `mlock` doesn't nest, unlocking one secret would also unlock every other
+/// secret sharing a page with it. A page is only unlocked with its last one.
+static LOCKED_PAGES: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+fn page_size() -> usize {
+    static PAGE_SIZE: OnceLock<usize> = OnceLock::new();
+    // SAFETY: sysconf has no preconditions.
+    *PAGE_SIZE.get_or_init(|| match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _ => 4096,
+    })
+}
+
+/// Start addresses of the pages spanned by `len` bytes at `ptr`.
+fn pages(ptr: *const u8, len: usize) -> impl Iterator<Item = usize> {
+    let size = page_size();
+    let first = ptr as usize / size * size;
+    let last = (ptr as usize + len - 1) / size * size;
+    (first..=last).step_by(size)
+}
+
+/// Locks the pages backing `bytes` into RAM so they never reach swap.
+///
+/// Locking is best effort, it fails quietly when the process is out of
+/// `RLIMIT_MEMLOCK` and the secret is then only wiped on drop.
+fn lock(bytes: &[u8]) -> bool {
+    if bytes.is_empty() {
+        return false;
+    }
+    let mut locked = LOCKED_PAGES.lock().unwrap_or_else(|e| e.into_inner());
+    let mut done = vec![];
+    for page in pages(bytes.as_ptr(), bytes.len()) {
+        let count = locked.entry(page).or_insert(0);
+        // SAFETY: the page holds part of a live allocation owned by the caller.
+        if *count == 0 && unsafe { libc::mlock(page as *const libc::c_void, page_size()) } != 0 {
+            locked.remove(&page);
+            release(&mut locked, done);
+            return false;
+        }
+        *count += 1;
+        done.push(page);
+    }
+    true
+}
+
+fn unlock(ptr: *const u8, len: usize) {
+    let mut locked = LOCKED_PAGES.lock().unwrap_or_else(|e| e.into_inner());
+    release(&mut locked, pages(ptr, len));
+}
+
+/// Drops one secret from each of `pages`, unlocking the pages it was the
+/// last one on.
+fn release(locked: &mut BTreeMap<usize, usize>, pages: impl IntoIterator<Item = usize>) {
+    for page in pages {
+        let Some(count) = locked.get_mut(&page) else {
+            continue;
+        };
+        *count -= 1;
+        if *count == 0 {
+            locked.remove(&page);
+            // SAFETY: the page was locked by `lock` and is still allocated.
+            unsafe {
+                libc::munlock(page as *const libc::c_void, page_size());
+            }
+        }
+    }
+}
+
+/// The core dump limit the process started with, restored for programs it
+/// executes.
+static CORE_LIMIT: OnceLock<libc::rlimit> = OnceLock::new();
+
+/// Disables core dumps for the current process, so a crash can't write
+/// unlocked vault contents to disk.
+///
+/// Only the soft limit is lowered, a lowered hard limit could not be raised
+/// again for programs candado executes, see [`restore_process`].
+pub fn harden_process() {
+    let mut original = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `original` is valid for writes.
+    if unsafe { libc::getrlimit(libc::RLIMIT_CORE, &mut original) } != 0 {
+        original.rlim_max = 0;
+    }
+    let original = *CORE_LIMIT.get_or_init(|| original);
+    let limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: original.rlim_max,
+    };
+    // SAFETY: plain syscalls with valid arguments.
+    unsafe {
+        libc::setrlimit(libc::RLIMIT_CORE, &limit);
+        libc::prctl(libc::PR_SET_DUMPABLE, 0);
+    }
+}
+
+/// Gives back the core dump limit [`harden_process`] lowered, for a program
+/// that is about to replace candado. Only makes async-signal-safe calls, so it
+/// can run between fork and exec.
+pub fn restore_process() {
+    if let Some(limit) = CORE_LIMIT.get() {
+        // SAFETY: plain syscall with a valid argument.
+        unsafe {
+            libc::setrlimit(libc::RLIMIT_CORE, limit);
+        }
+    }
+}
+
+/// Key material that is locked in memory and wiped on drop.
+///
+/// Intentionally not `Debug`, use [`SecretBytes::expose`] to read it.
+pub struct SecretBytes {
+    inner: Box<[u8]>,
+    locked: bool,
+}
+
+impl SecretBytes {
+    pub fn new(mut bytes: Vec<u8>) -> Self {
+        // Copy into an exact sized allocation instead of shrinking the vec,
+        // a reallocation would leave an unwiped copy behind.
+        let inner: Box<[u8]> = bytes.as_slice().into();
+        bytes.zeroize();
+        let locked = lock(&inner);
+        Self { inner, locked }
+    }
+
+    pub fn expose(&self) -> &[u8] {
+        &self.inner
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
+
+impl From<&[u8]> for SecretBytes {
+    fn from(value: &[u8]) -> Self {
+        SecretBytes::new(value.to_vec())
+    }
+}
+
+impl Clone for SecretBytes {
+    fn clone(&self) -> Self {
+        SecretBytes::from(self.expose())
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        self.inner.zeroize();
+        if self.locked {
+            unlock(self.inner.as_ptr(), self.inner.len());
+        }
+    }
+}
+
+/// Text counterpart of [`SecretBytes`] for passwords and masters.
+#[derive(Default)]
+pub struct SecretString {
+    inner: String,
+    locked: bool,
+}
+
+impl SecretString {
+    pub fn new(inner: String) -> Self {
+        let locked = lock(inner.as_bytes());
+        Self { inner, locked }
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.inner
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(value: String) -> Self {
+        SecretString::new(value)
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(value: &str) -> Self {
+        SecretString::new(value.to_owned())
+    }
+}
+
+impl Clone for SecretString {
+    fn clone(&self) -> Self {
+        SecretString::from(self.expose())
+    }
+}
+
+impl PartialEq for SecretString {
+    fn eq(&self, other: &Self) -> bool {
+        self.inner == other.inner
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        // Zeroizing a string truncates it but keeps the allocation, so the
+        // range has to be taken before wiping.
+        let (ptr, len) = (self.inner.as_ptr(), self.inner.len());
+        self.inner.zeroize();
+        if self.locked {
+            unlock(ptr, len);
+        }
+    }
+}
+
+impl Serialize for SecretString {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.inner)
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(SecretString::new(String::deserialize(deserializer)?))
+    }
+}
@@ -0,0 +1,96 @@
+use anyhow::{anyhow, Result};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use zeroize::Zeroizing;
+
+/// Reduction polynomial of GF(256), x^8 + x^4 + x^3 + x^2 + 1, the same as
+/// the `sharks` crate used, so shares made by earlier releases still combine.
+const POLYNOMIAL: u16 = 0x11d;
+
+/// Multiplies in GF(256) without branching on the values.
+fn mul(a: u8, b: u8) -> u8 {
+    let (mut a, mut b) = (a as u16, b);
+    let mut product = 0u16;
+    for _ in 0..8 {
+        product ^= a & (b & 1).wrapping_neg() as u16;
+        a <<= 1;
+        a ^= POLYNOMIAL & (a >> 8).wrapping_neg();
+        b >>= 1;
+    }
+    product as u8
+}
+
+/// Inverts a non-zero element as a^254.
+fn inv(a: u8) -> u8 {
+    let mut result = 1;
+    let mut power = a;
+    let mut exponent = 254u8;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = mul(result, power);
+        }
+        power = mul(power, power);
+        exponent >>= 1;
+    }
+    result
+}
+
+/// Splits `secret` with Shamir's secret sharing, byte by byte, into `shares`
+/// shares at x = 1, 2, .., any `threshold` of which give it back with
+/// [`combine`]. A share is `x` followed by one `y` per byte of `secret`.
+///
+/// Every coefficient is drawn from the whole field. `sharks` never drew 0,
+/// which let fewer than `threshold` shares rule out values of the secret.
+pub fn split(secret: &[u8], threshold: u8, shares: u8) -> Vec<Zeroizing<Vec<u8>>> {
+    // One polynomial per secret byte, the byte is its constant term.
+    let degree = threshold as usize - 1;
+    let mut coefficients = Zeroizing::new(vec![0u8; secret.len() * degree]);
+    OsRng.fill_bytes(&mut coefficients);
+
+    (1..=shares)
+        .map(|x| {
+            let mut share = Zeroizing::new(Vec::with_capacity(secret.len() + 1));
+            share.push(x);
+            for (&byte, terms) in secret.iter().zip(coefficients.chunks(degree)) {
+                // Horner's rule, highest degree first.
+                let y = terms.iter().fold(0, |acc, &c| mul(acc, x) ^ c);
+                share.push(mul(y, x) ^ byte);
+            }
+            share
+        })
+        .collect()
+}
+
+/// Recovers the secret from `threshold` or more shares made by [`split`].
+pub fn combine(shares: &[&[u8]], threshold: u8) -> Result<Zeroizing<Vec<u8>>> {
+    let mut points: Vec<&[u8]> = vec![];
+    for share in shares {
+        let (&x, y) = share
+            .split_first()
+            .ok_or_else(|| anyhow!("The share is not valid."))?;
+        if x == 0 || y.len() != points.first().map_or(y.len(), |p| p.len() - 1) {
+            return Err(anyhow!("The share is not valid."));
+        }
+        if !points.iter().any(|p| p[0] == x) {
+            points.push(share);
+        }
+    }
+    if points.len() < threshold as usize {
+        return Err(anyhow!("Not enough different shares"));
+    }
+    points.truncate(threshold as usize);
+
+    // Lagrange interpolation at x = 0, subtraction is xor in GF(256).
+    let mut secret = Zeroizing::new(vec![0u8; points[0].len() - 1]);
+    for point in &points {
+        let basis = points
+            .iter()
+            .filter(|other| other[0] != point[0])
+            .fold(1, |acc, other| {
+                mul(acc, mul(other[0], inv(other[0] ^ point[0])))
+            });
+        for (byte, &y) in secret.iter_mut().zip(&point[1..]) {
+            *byte ^= mul(basis, y);
+        }
+    }
+    Ok(secret)
+}
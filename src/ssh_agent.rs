@@ -0,0 +1,257 @@
+use anyhow::{anyhow, Result};
+use argon2::password_hash::rand_core::OsRng;
+use rsa::pkcs1v15::SigningKey;
+use rsa::sha2::{Sha256, Sha512};
+use rsa::signature::{RandomizedSigner, SignatureEncoding, Signer};
+use rsa::BigUint;
+use ssh_key::private::{KeypairData, RsaKeypair};
+use ssh_key::{Mpint, PrivateKey, Signature};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use crate::agent::{self, Activity, Agent, CLIENT_TIMEOUT};
+use crate::error::CandadoError;
+use crate::vault_dir::VaultDir;
+
+pub const SSH_SOCKET_NAME: &str = "ssh-agent.sock";
+
+// Message numbers of the OpenSSH agent protocol, see draft-miller-ssh-agent.
+const SSH_AGENT_FAILURE: u8 = 5;
+const SSH_AGENT_SUCCESS: u8 = 6;
+const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+const SSH_AGENT_SIGN_RESPONSE: u8 = 14;
+const SSH_AGENTC_REMOVE_ALL_IDENTITIES: u8 = 19;
+
+// Sign request flags picking the hash of an rsa signature.
+const SSH_AGENT_RSA_SHA2_256: u32 = 2;
+const SSH_AGENT_RSA_SHA2_512: u32 = 4;
+
+/// Largest request accepted, the same limit OpenSSH uses.
+const MAX_MESSAGE_LEN: usize = 256 * 1024;
+
+/// A key served by the agent, with its public key in wire format as clients
+/// refer to it.
+pub(crate) struct Identity {
+    blob: Vec<u8>,
+    key: PrivateKey,
+}
+
+/// Parses the OpenSSH private key of an [`crate::EntryKind::SshKey`] entry.
+///
+/// Only unencrypted ed25519 and rsa keys are accepted, the vault encrypts
+/// them already and the agent has no way to ask for a passphrase.
+pub fn parse_key(private_key: &str) -> Result<PrivateKey> {
+    let key = PrivateKey::from_openssh(private_key)
+        .map_err(|e| CandadoError::InvalidInput(format!("Not an OpenSSH private key: {e}")))?;
+    if key.is_encrypted() {
+        return Err(CandadoError::InvalidInput(
+            "The SSH key is protected by a passphrase -> Remove it with 'ssh-keygen -p -f <file>', the vault encrypts the key instead.".to_owned(),
+        )
+        .into());
+    }
+    match key.key_data() {
+        KeypairData::Ed25519(_) => Ok(key),
+        KeypairData::Rsa(keypair) => rsa_key(keypair).map(|_| key),
+        _ => Err(CandadoError::InvalidInput(format!(
+            "Unsupported SSH key type '{}' -> Use an ed25519 or rsa key.",
+            key.algorithm()
+        ))
+        .into()),
+    }
+}
+
+/// Whether an SSH agent of `vault` is listening on its socket.
+pub fn is_running(vault: &VaultDir) -> bool {
+    UnixStream::connect(vault.ssh_socket()).is_ok()
+}
+
+/// Tells a running SSH agent to drop its keys and exit. Returns `false` if no
+/// agent was running.
+pub fn stop(vault: &VaultDir) -> Result<bool> {
+    let Ok(mut stream) = UnixStream::connect(vault.ssh_socket()) else {
+        return Ok(false);
+    };
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    write_message(&mut stream, &[SSH_AGENTC_REMOVE_ALL_IDENTITIES])?;
+    if read_message(&mut stream)?.as_deref() != Some(&[SSH_AGENT_SUCCESS]) {
+        return Err(anyhow!("Unexpected answer from SSH agent"));
+    }
+    agent::wait_closed(&vault.ssh_socket());
+    Ok(true)
+}
+
+/// Binds the SSH agent socket of `vault`, to serve `keys` until they are
+/// removed or the agent is idle for `timeout`.
+pub fn bind_agent(vault: &VaultDir, keys: Vec<PrivateKey>, timeout: Duration) -> Result<Agent> {
+    let identities = identities(keys)?;
+    Agent::bind(vault.ssh_socket(), move |listener| {
+        agent::serve_clients(listener, timeout, move |stream, activity| {
+            handle(stream, &identities, activity)
+        })
+    })
+}
+
+/// Pairs each key with its public key in wire format.
+pub(crate) fn identities(keys: Vec<PrivateKey>) -> Result<Vec<Identity>> {
+    keys.into_iter()
+        .map(|key| {
+            Ok(Identity {
+                blob: key.public_key().to_bytes()?,
+                key,
+            })
+        })
+        .collect()
+}
+
+/// Answers the requests of one ssh client until it hangs up.
+pub(crate) fn handle(
+    mut stream: UnixStream,
+    identities: &[Identity],
+    activity: &Activity,
+) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    // SAFETY: getuid has no preconditions.
+    if agent::peer_uid(&stream)? != unsafe { libc::getuid() } {
+        return Err(anyhow!("Rejected client of another user"));
+    }
+
+    while let Some(request) = read_message(&mut stream)? {
+        activity.touch();
+        let response = match request.split_first() {
+            Some((&SSH_AGENTC_REQUEST_IDENTITIES, _)) => list(identities),
+            Some((&SSH_AGENTC_SIGN_REQUEST, body)) => {
+                sign(body, identities).unwrap_or_else(|_| vec![SSH_AGENT_FAILURE])
+            }
+            Some((&SSH_AGENTC_REMOVE_ALL_IDENTITIES, _)) => {
+                activity.stop();
+                vec![SSH_AGENT_SUCCESS]
+            }
+            // Adding keys, locking and extensions are left to the vault
+            _ => vec![SSH_AGENT_FAILURE],
+        };
+        write_message(&mut stream, &response)?;
+    }
+    Ok(())
+}
+
+fn list(identities: &[Identity]) -> Vec<u8> {
+    let mut response = vec![SSH_AGENT_IDENTITIES_ANSWER];
+    put_u32(&mut response, identities.len() as u32);
+    for identity in identities {
+        put_string(&mut response, &identity.blob);
+        put_string(&mut response, identity.key.comment().as_bytes());
+    }
+    response
+}
+
+fn sign(mut body: &[u8], identities: &[Identity]) -> Result<Vec<u8>> {
+    let blob = get_string(&mut body)?;
+    let data = get_string(&mut body)?;
+    let flags = get_u32(&mut body)?;
+    let identity = identities
+        .iter()
+        .find(|identity| identity.blob == blob)
+        .ok_or_else(|| anyhow!("Unknown key"))?;
+
+    // RSA signs with a random number generator so the private operation is
+    // blinded, signing without one leaks timing (RUSTSEC-2023-0071).
+    let (algorithm, signature) = match identity.key.key_data() {
+        KeypairData::Rsa(keypair) if flags & SSH_AGENT_RSA_SHA2_256 != 0 => {
+            let key = SigningKey::<Sha256>::new(rsa_key(keypair)?);
+            let signature = key.try_sign_with_rng(&mut OsRng, data)?;
+            ("rsa-sha2-256".to_owned(), signature.to_vec())
+        }
+        KeypairData::Rsa(keypair) if flags & SSH_AGENT_RSA_SHA2_512 != 0 => {
+            let key = SigningKey::<Sha512>::new(rsa_key(keypair)?);
+            let signature = key.try_sign_with_rng(&mut OsRng, data)?;
+            ("rsa-sha2-512".to_owned(), signature.to_vec())
+        }
+        // Plain ssh-rsa signs with sha-1, which OpenSSH turned off as well
+        KeypairData::Rsa(_) => return Err(anyhow!("SHA-1 rsa signatures are not supported")),
+        _ => {
+            let signature: Signature = identity.key.try_sign(data)?;
+            (
+                signature.algorithm().as_str().to_owned(),
+                signature.as_bytes().to_vec(),
+            )
+        }
+    };
+
+    let mut encoded = vec![];
+    put_string(&mut encoded, algorithm.as_bytes());
+    put_string(&mut encoded, &signature);
+    let mut response = vec![SSH_AGENT_SIGN_RESPONSE];
+    put_string(&mut response, &encoded);
+    Ok(response)
+}
+
+/// The rsa crate key of `keypair`. Assembled from its components here, the
+/// conversion of ssh-key 0.6 passes the prime `p` twice and fails.
+fn rsa_key(keypair: &RsaKeypair) -> Result<rsa::RsaPrivateKey> {
+    let uint = |mpint: &Mpint| {
+        mpint
+            .as_positive_bytes()
+            .map(BigUint::from_bytes_be)
+            .ok_or_else(|| anyhow!("Invalid rsa key"))
+    };
+    Ok(rsa::RsaPrivateKey::from_components(
+        uint(&keypair.public.n)?,
+        uint(&keypair.public.e)?,
+        uint(&keypair.private.d)?,
+        vec![uint(&keypair.private.p)?, uint(&keypair.private.q)?],
+    )?)
+}
+
+/// Reads one length prefixed message, `None` once the client hung up.
+pub(crate) fn read_message(stream: &mut UnixStream) -> Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len == 0 || len > MAX_MESSAGE_LEN {
+        return Err(anyhow!("Invalid message length {len}"));
+    }
+    let mut message = vec![0u8; len];
+    stream.read_exact(&mut message)?;
+    Ok(Some(message))
+}
+
+pub(crate) fn write_message(stream: &mut UnixStream, message: &[u8]) -> Result<()> {
+    let mut framed = Vec::with_capacity(message.len() + 4);
+    put_string(&mut framed, message);
+    stream.write_all(&framed)?;
+    Ok(())
+}
+
+pub(crate) fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+pub(crate) fn put_string(out: &mut Vec<u8>, value: &[u8]) {
+    put_u32(out, value.len() as u32);
+    out.extend_from_slice(value);
+}
+
+pub(crate) fn get_u32(input: &mut &[u8]) -> Result<u32> {
+    let (value, rest) = input
+        .split_first_chunk::<4>()
+        .ok_or_else(|| anyhow!("Truncated message"))?;
+    *input = rest;
+    Ok(u32::from_be_bytes(*value))
+}
+
+pub(crate) fn get_string<'a>(input: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let len = get_u32(input)? as usize;
+    if input.len() < len {
+        return Err(anyhow!("Truncated message"));
+    }
+    let (value, rest) = input.split_at(len);
+    *input = rest;
+    Ok(value)
+}
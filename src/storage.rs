@@ -1,56 +1,90 @@
-use std::{fs::{self, File, Permissions}, io::Write, os::unix::fs::PermissionsExt, path::{Path, PathBuf}};
+use std::{
+    fs::{self, File, Permissions},
+    io::Write,
+    os::unix::fs::PermissionsExt,
+    path::PathBuf,
+    time::Duration,
+};
 
-use crate::{entry::{Decrypt, Encrypt, EncryptedEntry, Entry, ImportedEntry}, Encrypter, PROGRAM_FOLDER};
-use anyhow::{anyhow, Result};
+use crate::{
+    entry::{associated_data, Decrypt, Encrypt, EncryptedEntry, Entry, EntryKind, ImportedEntry},
+    error::CandadoError,
+    vault_dir::VaultLock,
+    Encrypter,
+};
+use anyhow::Result;
 use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
-use rusqlite::{params, Connection};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use zeroize::Zeroizing;
 
+/// Layout of the rows in `candado.db`, stored in sqlite's `user_version`.
+const SCHEMA_VERSION: i64 = 3;
+
+/// How long a statement waits for another connection to release the database.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[allow(clippy::upper_case_acronyms)]
 pub enum SupportedFile {
     JSON(String),
     SQL(String),
 }
 
-pub struct Storage<'unlocked> {
+pub struct Storage {
     conn: Connection,
-    encrypter: &'unlocked Encrypter,
+    encrypter: Encrypter,
+    path: PathBuf,
+}
+
+/// A row that could not be read back from the vault
+#[derive(Debug)]
+pub struct RowFailure {
+    pub row: i64,
+    pub entry_id: Option<String>,
+    pub error: String,
+}
+
+/// Result of [`Storage::verify`]
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub entries: usize,
+    pub failures: Vec<RowFailure>,
+    pub duplicates: Vec<String>,
+    pub issues: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty() && self.duplicates.is_empty() && self.issues.is_empty()
+    }
 }
 
-impl<'unlocked> Storage<'unlocked> {
+impl Storage {
     /// Initialize storage
     ///
     /// # Params
     /// * Needs a Encrypter instace to encrypt & decrypt entries
-    /// 
+    ///
     /// # Panics
     /// This function will panic if:
     /// * fails to create and set permission of db
     /// * fails to connect to db
     ///
     /// # Basic usage:
-    /// 
+    ///
     /// let password: &str = "password";
     /// let enc = Encrypter::unlock(password)?;
-    /// let storage = Storage::init(&encrypter)?;
-    /// 
-    pub fn init(encrypter: &'unlocked Encrypter) -> Result<Self> {
-        // Linux
-        #[cfg(target_os = "linux")]
-        let db_path = format!("{}/{}/candado.db", std::env::var("HOME")?, PROGRAM_FOLDER);
-
-        // NOTE: Support for Mac os and Windows will be added in the future
-        // MacOs
-        // #[cfg(target_os = "macos")]
-        // let db_path = format!("{}/.candado/candado.db", std::env::var("HOME")?);
-        // windows
-        // #[cfg(target_os = "windows")]
-        // let db_path = format!("{}/.candado/.candado.db", std::env::var("USERHOME")?);
-
-        let db_path = Path::new(&db_path);
+    /// let storage = Storage::init(encrypter)?;
+    ///
+    pub fn init(encrypter: Encrypter) -> Result<Self> {
+        let db_path = encrypter.vault().database();
         if !db_path.exists() {
-            File::create(db_path)?.set_permissions(Permissions::from_mode(0o600))?;
+            File::create(&db_path)
+                .and_then(|db| db.set_permissions(Permissions::from_mode(0o600)))
+                .map_err(CandadoError::Io)?;
         }
 
-        let conn = Connection::open(db_path)?;
+        let conn = Connection::open(&db_path).map_err(CandadoError::Database)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
         conn.execute(
             "CREATE TABLE IF NOT EXISTS candado (
             id INTEGER PRIMARY KEY,
@@ -63,179 +97,516 @@ impl<'unlocked> Storage<'unlocked> {
         )",
             [],
         )?;
-        let storage = Self { conn, encrypter };
+        let mut storage = Self {
+            conn,
+            encrypter,
+            path: db_path,
+        };
+        storage.migrate()?;
+        storage.resume_rotation()?;
         Ok(storage)
     }
 
+    /// Finishes a key rotation that stopped after the rows were committed but
+    /// before the keyfile was replaced
+    ///
+    /// Such rows only open with the staged keyfile of that rotation. If the
+    /// first row opens with one and not with the current key, that keyfile is
+    /// moved into place and used from here on. If it opens with the keyfile on
+    /// disk instead, another process rotated the key after this one was
+    /// unlocked and the new key is picked up. Staged keyfiles of rotations
+    /// that never got to commit their rows are dropped, as are all of them in
+    /// an empty vault.
+    ///
+    /// Runs holding the lock of the vault, so it never sees the rows of a
+    /// rotation that is still in progress.
+    fn resume_rotation(&mut self) -> Result<()> {
+        let vault = self.encrypter.vault().clone();
+        let _lock = vault.lock()?;
+        let mut rotations = self.encrypter.staged_rotations()?;
+        let mut stmt = self.conn.prepare("SELECT * FROM candado LIMIT 1")?;
+        let sample = stmt.query_row([], encrypted_entry).optional()?;
+        drop(stmt);
+
+        if let Some(sample) = sample.filter(|sample| sample.decrypt(&self.encrypter).is_err()) {
+            let resumed = rotations.iter().position(|(_, rotated)| {
+                rotated
+                    .as_ref()
+                    .is_some_and(|rotated| sample.decrypt(rotated).is_ok())
+            });
+            match resumed.map(|i| rotations.remove(i)) {
+                Some((staged, Some(rotated))) => {
+                    Encrypter::commit_keyfile(&staged)?;
+                    self.encrypter = rotated;
+                }
+                _ => match self.encrypter.reload() {
+                    Ok(current) if sample.decrypt(&current).is_ok() => self.encrypter = current,
+                    // A damaged row, `vault check` reports it. Any staged
+                    // keyfile is kept, it may be the only key of the rows.
+                    _ => return Ok(()),
+                },
+            }
+        }
+        for (staged, _) in rotations {
+            fs::remove_file(staged)?;
+        }
+        Ok(())
+    }
+
+    /// Upgrades the rows of an older vault to the current schema
+    ///
+    /// Version 0 encrypted every column, the entry id included, without
+    /// associated data. Version 1 keeps the entry id in plain text and binds
+    /// each field to its entry and column, so moved or swapped ciphertexts
+    /// no longer decrypt. Version 2 adds the encrypted custom fields and
+    /// version 3 the kind of each entry.
+    ///
+    /// Rows that can't be decrypted are skipped instead of failing the whole
+    /// upgrade, [`Storage::verify`] reports them afterwards.
+    ///
+    /// Runs holding the lock of the vault and reads the version only then, so
+    /// a vault opened by two processes at once is upgraded exactly once.
+    fn migrate(&mut self) -> Result<()> {
+        let vault = self.encrypter.vault().clone();
+        let _lock = vault.lock()?;
+        let version: i64 = self
+            .conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version >= SCHEMA_VERSION {
+            return Ok(());
+        }
+        if version < 1 {
+            self.migrate_v1()?;
+        }
+        if version < 2 {
+            self.add_column("custom", "{}", 2)?;
+        }
+        if version < 3 {
+            self.add_column("kind", EntryKind::Login.as_str(), 3)?;
+        }
+        Ok(())
+    }
+
+    fn migrate_v1(&mut self) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmt = tx.prepare("SELECT * FROM candado")?;
+            let rows = stmt.query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    [
+                        row.get::<_, Vec<u8>>(1)?,
+                        row.get::<_, Vec<u8>>(2)?,
+                        row.get::<_, Vec<u8>>(3)?,
+                        row.get::<_, Vec<u8>>(4)?,
+                        row.get::<_, Vec<u8>>(5)?,
+                        row.get::<_, Vec<u8>>(6)?,
+                    ],
+                ))
+            })?;
+            for row in rows {
+                let (rowid, [id, service, email, password, username, url]) = row?;
+                let decrypt = |field: &[u8]| self.encrypter.decrypt(field);
+                let entry = (|| -> Result<Entry> {
+                    Ok(Entry::init(
+                        decrypt(&id)?,
+                        decrypt(&service)?,
+                        decrypt(&email)?,
+                        decrypt(&password)?,
+                        decrypt(&username)?,
+                        decrypt(&url)?,
+                    ))
+                })();
+                // A damaged row is left as it is, so the rest of the vault still
+                // opens and `vault check` can report it
+                let Ok(entry) = entry else {
+                    continue;
+                };
+                let entry = entry.encrypt(&self.encrypter)?;
+                tx.execute(
+                    "UPDATE candado SET entry_id=?2, service=?3, email=?4, password=?5, username=?6, url=?7 WHERE id=?1",
+                    params![
+                        rowid,
+                        entry.id,
+                        entry.service,
+                        entry.email,
+                        entry.password,
+                        entry.username,
+                        entry.url,
+                    ],
+                )?;
+            }
+        }
+        tx.pragma_update(None, "user_version", 1)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Adds the encrypted field `column`, set to `value` in every entry.
+    fn add_column(&mut self, column: &str, value: &str, version: i64) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            &format!("ALTER TABLE candado ADD COLUMN {column} BLOB NOT NULL DEFAULT x''"),
+            [],
+        )?;
+        {
+            let mut stmt = tx.prepare("SELECT id, entry_id FROM candado")?;
+            let rows = stmt.query_map([], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1).ok()))
+            })?;
+            for row in rows {
+                // Rows the v1 migration couldn't read have no plain entry id
+                let (rowid, Some(id)) = row? else {
+                    continue;
+                };
+                let payload = self
+                    .encrypter
+                    .encrypt_with(value, &associated_data(&id, column))?;
+                tx.execute(
+                    &format!("UPDATE candado SET {column}=?2 WHERE id=?1"),
+                    params![rowid, payload],
+                )?;
+            }
+        }
+        tx.pragma_update(None, "user_version", version)?;
+        tx.commit()?;
+        Ok(())
+    }
+
     /// write a single entry to the vault
-    /// 
+    ///
     /// # Panics
     /// This function will panic if:
     /// * fails to write to db
     /// * can't encrypt entry
     ///
     /// # Basic usage:
-    /// 
+    ///
     /// let password: &str = "password";
     /// let enc = Encrypter::unlock(password)?;
-    /// let storage = Storage::init(&encrypter)?;
+    /// let storage = Storage::init(encrypter)?;
     /// let entry = Entry::default;
-    /// let result = storage.write(entry); 
-    /// 
+    /// let result = storage.write(entry);
+    ///
     pub fn write<T: Encrypt>(&self, entry: T) -> Result<()> {
+        let _lock = self.lock_current()?;
         let entry = entry.encrypt(&self.encrypter)?;
         self.conn.execute(
-            "INSERT INTO candado (entry_id, service, email, password, username, url) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            "INSERT INTO candado (entry_id, service, email, password, username, url, custom, kind) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
             params![
-                entry.id, 
-                entry.service, 
-                entry.email, 
-                entry.password, 
-                entry.username, 
+                entry.id,
+                entry.service,
+                entry.email,
+                entry.password,
+                entry.username,
                 entry.url,
+                entry.custom,
+                entry.kind,
             ],
         )?;
         Ok(())
     }
 
     /// removes a single entry by id from the vault
-    /// 
+    ///
     /// # Panics
     /// This function will panic if:
     /// * fails to write to db
     ///
     /// # Basic usage:
-    /// 
+    ///
     /// let password: &str = "password";
     /// let enc = Encrypter::unlock(password)?;
-    /// let storage = Storage::init(&encrypter)?;
+    /// let storage = Storage::init(encrypter)?;
     /// let result = storage.delete("jkdfnF54ms");
-    /// 
+    ///
     pub fn remove(&self, id: &str) -> Result<()> {
-        self.conn.execute(
-            "DELETE FROM candado WHERE entry_id=?1",
-            params![id],
-        )?;
+        let removed = self
+            .conn
+            .execute("DELETE FROM candado WHERE entry_id=?1", params![id])?;
+        if removed == 0 {
+            return Err(CandadoError::NotFound(id.to_owned()).into());
+        }
         Ok(())
     }
 
     /// read a single entry by id from the vault
-    /// 
+    ///
     /// # Panics
     /// This function will panic if:
     /// * fails to read from db
     /// * can't decrypt the entries
     ///
     /// # Basic usage:
-    /// 
+    ///
     /// let password: &str = "password";
     /// let enc = Encrypter::unlock(password)?;
-    /// let storage = Storage::init(&encrypter)?;
+    /// let storage = Storage::init(encrypter)?;
     /// let entry: Entry = storage.read("jkdfnF54ms")?;
-    /// 
+    ///
     pub fn read(&self, id: &str) -> Result<Entry> {
-        let mut stmt = self.conn.prepare("SELECT * FROM candado WHERE entry_id=?1")?;
-        let entry = stmt.query_row(params![id], |row| {
-            Ok(EncryptedEntry{
-                id: row.get(1)?,
-                service: row.get(2)?,
-                email: row.get(3)?,
-                password: row.get(4)?,
-                username: row.get(5)?,
-                url: row.get(6)?,
-            })
-        })?;
+        let mut stmt = self
+            .conn
+            .prepare("SELECT * FROM candado WHERE entry_id=?1")?;
+        let entry = stmt
+            .query_row(params![id], encrypted_entry)
+            .map_err(|e| match e {
+                rusqlite::Error::QueryReturnedNoRows => CandadoError::NotFound(id.to_owned()),
+                e => CandadoError::Database(e),
+            })?;
         let entry = entry.decrypt(&self.encrypter)?;
         Ok(entry)
     }
 
     /// updates an entry in the vault
-    /// 
+    ///
     /// # Panics
     /// This function will panic if:
     /// * fails to write to db
     /// * can't encrypt the entry
     ///
     /// # Basic usage:
-    /// 
+    ///
     /// let password: &str = "password";
     /// let enc = Encrypter::unlock(password)?;
-    /// let storage = Storage::init(&encrypter)?;
+    /// let storage = Storage::init(encrypter)?;
     /// let entries: Vec<Entry> = storage.find("some service")?;
-    /// 
+    ///
     pub fn update<T: Encrypt>(&self, entry: T) -> Result<()> {
+        let _lock = self.lock_current()?;
         let entry = entry.encrypt(&self.encrypter)?;
-        self.conn.execute(
-            "UPDATE candado SET service=?2, email=?3, password=?4, username=?5, url=?6 WHERE entry_id=?1",
+        let updated = self.conn.execute(
+            "UPDATE candado SET service=?2, email=?3, password=?4, username=?5, url=?6, custom=?7, kind=?8 WHERE entry_id=?1",
             params![
-                entry.id, 
-                entry.service, 
-                entry.email, 
-                entry.password, 
-                entry.username, 
+                entry.id,
+                entry.service,
+                entry.email,
+                entry.password,
+                entry.username,
                 entry.url,
+                entry.custom,
+                entry.kind,
             ],
         )?;
+        if updated == 0 {
+            return Err(CandadoError::NotFound(entry.id).into());
+        }
         Ok(())
     }
 
     /// gets a list of decrypted entries from vault matching the query
-    /// 
+    ///
     /// # Panics
     /// This function will panic if:
     /// * fails to read db
     /// * can't decrypt entries
     ///
     /// # Basic usage:
-    /// 
+    ///
     /// let password: &str = "password";
     /// let enc = Encrypter::unlock(password)?;
-    /// let storage = Storage::init(&encrypter)?;
+    /// let storage = Storage::init(encrypter)?;
     /// let entries: Vec<Entry> = storage.find("some service")?;
-    /// 
+    ///
     pub fn find(&self, query: &str) -> Result<Vec<Entry>> {
         let matcher = SkimMatcherV2::default();
         let entries = self.list()?;
-        let result: Vec<Entry> = entries.iter().filter_map(|entry| {
-            matcher.fuzzy_match(&format!("{}", entry), query).map(|_| entry.clone())
-        }).collect();
+        let result: Vec<Entry> = entries
+            .iter()
+            .filter_map(|entry| {
+                matcher
+                    .fuzzy_match(&Zeroizing::new(format!("{}", entry)), query)
+                    .map(|_| entry.clone())
+            })
+            .collect();
         Ok(result)
     }
 
     /// gets a list of decrypted entries from vault
-    /// 
+    ///
     /// # Panics
     /// This function will panic if:
     /// * fails to read db
     /// * can't decrypt entries
     ///
     /// # Basic usage:
-    /// 
+    ///
     /// let password: &str = "password";
     /// let enc = Encrypter::unlock(password)?;
-    /// let storage = Storage::init(&encrypter)?;
+    /// let storage = Storage::init(encrypter)?;
     /// let entries: Vec<Entry> = storage.list()?;
-    /// 
+    ///
     pub fn list(&self) -> Result<Vec<Entry>> {
         let mut stmt = self.conn.prepare("SELECT * FROM candado")?;
-        let enries = stmt.query_map([], |row| {
-            Ok(EncryptedEntry {
-                id: row.get(1)?,
-                service: row.get(2)?,
-                email: row.get(3)?,
-                password: row.get(4)?,
-                username: row.get(5)?,
-                url: row.get(6)?,
-            })
-        })?;
+        let enries = stmt.query_map([], encrypted_entry)?;
         let mut result: Vec<Entry> = vec![];
         for entry in enries {
-            let entry = entry.unwrap();
-            result.push(entry.decrypt(&self.encrypter)?);
+            let entry = entry?.decrypt(&self.encrypter).map_err(|e| {
+                CandadoError::Corrupt(format!(
+                    "{e:#} -> Use 'candado vault check' to find all damaged entries."
+                ))
+            })?;
+            result.push(entry);
         }
         Ok(result)
     }
 
+    /// checks the whole vault and reports every problem instead of stopping
+    /// at the first one
+    ///
+    /// Every row is decrypted, entry ids are checked for duplicates and the
+    /// keyfile and database are checked for structure and permissions.
+    ///
+    /// # Panics
+    /// This function will panic if:
+    /// * fails to read db
+    ///
+    /// # Basic usage:
+    ///
+    /// let password: &str = "password";
+    /// let enc = Encrypter::unlock(password)?;
+    /// let storage = Storage::init(encrypter)?;
+    /// let report: VerifyReport = storage.verify()?;
+    ///
+    pub fn verify(&self) -> Result<VerifyReport> {
+        let mut report = VerifyReport {
+            issues: self.encrypter.check_keyfile(),
+            ..Default::default()
+        };
+
+        let mode = fs::metadata(&self.path)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            report.issues.push(format!(
+                "Database permissions are {:o}, expected 600",
+                mode & 0o777
+            ));
+        }
+
+        let mut stmt = self.conn.prepare("SELECT * FROM candado")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            report.entries += 1;
+            let rowid: i64 = row.get(0)?;
+            let entry_id = row.get::<_, String>(1).ok();
+            let result = match encrypted_entry(row) {
+                Ok(entry) => entry.decrypt(&self.encrypter).map(|_| ()),
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = result {
+                report.failures.push(RowFailure {
+                    row: rowid,
+                    entry_id,
+                    error: format!("{e:#}"),
+                });
+            }
+        }
+
+        let mut stmt = self
+            .conn
+            .prepare("SELECT entry_id FROM candado GROUP BY entry_id HAVING COUNT(*) > 1")?;
+        report.duplicates = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(report)
+    }
+
+    /// re-encrypts every entry with the data key of `rotated` and swaps the
+    /// keyfile once all rows are written
+    ///
+    /// All rows are rewritten inside one transaction, so the vault either
+    /// ends up fully on the new key or stays untouched on the old one. If the
+    /// keyfile can't be swapped after the rows are committed, the next
+    /// [`Storage::init`] finishes the rotation. The vault stays locked until
+    /// the keyfile is swapped, other processes wait for it in
+    /// [`Storage::init`] and when writing the keyfile.
+    ///
+    /// # Panics
+    /// This function will panic if:
+    /// * can't decrypt an entry with the current key
+    /// * fails to write to db
+    /// * fails to write the keyfile
+    ///
+    /// # Basic usage:
+    ///
+    /// let password: &str = "password";
+    /// let enc = Encrypter::unlock(password)?;
+    /// let mut storage = Storage::init(encrypter)?;
+    /// let rotated = storage.encrypter().rotate(CipherSuite::XChaCha20Poly1305)?;
+    /// storage.rotate(rotated)?;
+    ///
+    pub fn rotate(&mut self, rotated: Encrypter) -> Result<()> {
+        let _lock = self.lock_current()?;
+        let staged = self.reencrypt(&rotated)?;
+        Encrypter::commit_keyfile(&staged)?;
+        self.encrypter = rotated;
+        Ok(())
+    }
+
+    /// first half of [`Storage::rotate`], stages the keyfile of `rotated` and
+    /// commits every row re-encrypted with it
+    ///
+    /// Returns the staged keyfile, the vault only opens with it until it is
+    /// committed or [`Storage::init`] finishes the rotation. The caller holds
+    /// the lock of the vault.
+    pub(crate) fn reencrypt(&mut self, rotated: &Encrypter) -> Result<PathBuf> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmt = tx.prepare("SELECT * FROM candado")?;
+            let rows = stmt.query_map([], encrypted_row)?;
+            for row in rows {
+                let (rowid, entry) = row?;
+                let entry = entry.decrypt(&self.encrypter)?.encrypt(rotated)?;
+                tx.execute(
+                    "UPDATE candado SET entry_id=?2, service=?3, email=?4, password=?5, username=?6, url=?7, custom=?8, kind=?9 WHERE id=?1",
+                    params![
+                        rowid,
+                        entry.id,
+                        entry.service,
+                        entry.email,
+                        entry.password,
+                        entry.username,
+                        entry.url,
+                        entry.custom,
+                        entry.kind,
+                    ],
+                )?;
+            }
+        }
+
+        let staged = rotated.stage_keyfile()?;
+        if let Err(e) = tx.commit() {
+            // The commit error stays the cause, a leftover staged keyfile is
+            // dropped by the next Storage::init
+            let error = anyhow::Error::from(CandadoError::Database(e));
+            return Err(match fs::remove_file(&staged) {
+                Ok(()) => error,
+                Err(cleanup) => error.context(format!(
+                    "Can't remove the staged keyfile {}: {cleanup}",
+                    staged.display()
+                )),
+            });
+        }
+        Ok(staged)
+    }
+
+    /// Takes the lock of the vault and checks that the keyfile still holds
+    /// the key of this storage. Rows written after another process rotated
+    /// the key would be sealed with the retired one, which nothing opens.
+    fn lock_current(&self) -> Result<VaultLock> {
+        let lock = self.encrypter.vault().lock()?;
+        self.encrypter.check_current()?;
+        Ok(lock)
+    }
+
+    /// The key this storage encrypts and decrypts entries with
+    pub fn encrypter(&self) -> &Encrypter {
+        &self.encrypter
+    }
+
+    pub(crate) fn encrypter_mut(&mut self) -> &mut Encrypter {
+        &mut self.encrypter
+    }
+
     pub fn load_json(source: PathBuf) -> Result<SupportedFile> {
         Ok(SupportedFile::JSON(fs::read_to_string(source)?))
     }
@@ -252,10 +623,10 @@ impl<'unlocked> Storage<'unlocked> {
     /// * cant't write to db
     ///
     /// # Basic usage:
-    /// 
+    ///
     /// let password: &str = "password";
     /// let enc = Encrypter::unlock(password)?;
-    /// let storage = Storage::init(&encrypter)?;
+    /// let storage = Storage::init(encrypter)?;
     /// storage.import("backup.json")?;
     ///
     pub fn import(&mut self, filepath: PathBuf) -> Result<()> {
@@ -263,10 +634,10 @@ impl<'unlocked> Storage<'unlocked> {
         let file = if let Some(extention) = filepath.extension() {
             match extention.to_str() {
                 Some("json") => Storage::load_json(filepath)?,
-                _ => return Err(anyhow!("File not supported")),
+                _ => return Err(CandadoError::InvalidInput("File not supported".to_owned()).into()),
             }
         } else {
-            return Err(anyhow!("Inalid filetype"));
+            return Err(CandadoError::InvalidInput("Inalid filetype".to_owned()).into());
         };
 
         match file {
@@ -277,14 +648,15 @@ impl<'unlocked> Storage<'unlocked> {
                 for (i, import) in entries.into_iter().enumerate() {
                     // loading bar
                     let percent = ((i + 1) as f64 / total as f64) * 100.0;
-                    let bar = "=".repeat(percent.ceil() as usize) + &" ".repeat((100.0 - percent).ceil() as usize);
+                    let bar = "=".repeat(percent.ceil() as usize)
+                        + &" ".repeat((100.0 - percent).ceil() as usize);
                     print!("\r[{}] {:.0}% | [{}/{}]", bar, percent, i + 1, total);
                     std::io::stdout().flush().unwrap();
                     self.write(Entry::from(import))?;
                 }
-                println!("");
+                println!();
             }
-            _ => todo!("import of this type are not supported yet")
+            _ => todo!("import of this type are not supported yet"),
         }
         Ok(())
     }
@@ -299,18 +671,40 @@ impl<'unlocked> Storage<'unlocked> {
     /// * can't serialize entries
     ///
     /// # Basic usage:
-    /// 
+    ///
     /// let password: &str = "password";
     /// let enc = Encrypter::unlock(password)?;
-    /// let storage = Storage::init(&encrypter)?;
+    /// let storage = Storage::init(encrypter)?;
     /// storage.export("backup.json")?;
     ///
     pub fn export(&self, path: PathBuf) -> Result<()> {
         let entries = self.list()?; // get all entries
-        let mut file = File::options().write(true).create(true).open(path)?;
+        let mut file = File::options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
         let objects = serde_json::to_string_pretty(&entries)?;
         writeln!(file, "{}", objects)?;
         Ok(())
     }
 }
 
+/// The entry of a `SELECT *` row.
+fn encrypted_entry(row: &Row) -> rusqlite::Result<EncryptedEntry> {
+    Ok(EncryptedEntry {
+        id: row.get(1)?,
+        service: row.get(2)?,
+        email: row.get(3)?,
+        password: row.get(4)?,
+        username: row.get(5)?,
+        url: row.get(6)?,
+        custom: row.get(7)?,
+        kind: row.get(8)?,
+    })
+}
+
+/// The rowid and entry of a `SELECT *` row.
+fn encrypted_row(row: &Row) -> rusqlite::Result<(i64, EncryptedEntry)> {
+    Ok((row.get(0)?, encrypted_entry(row)?))
+}
@@ -0,0 +1,91 @@
+use anyhow::{Context, Result};
+use std::fs::{self, File, Permissions};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use zeroize::Zeroizing;
+
+use crate::{error::CandadoError, SecretString};
+
+/// Scheme of the secret references in a template,
+/// `{{ candado://<id>/<field> }}`.
+pub const SCHEME: &str = "candado://";
+
+/// Replaces every `{{ candado://<id>/<field> }}` in `template` with the value
+/// `resolve` returns for that entry id and field.
+///
+/// Other `{{ ... }}` placeholders are kept as they are, so templates of other
+/// tools pass through. Fails on the first reference that can't be resolved,
+/// and on any placeholder that mentions the scheme without being a reference.
+pub fn inject(
+    template: &str,
+    resolve: impl Fn(&str, &str) -> Result<SecretString>,
+) -> Result<Zeroizing<String>> {
+    let mut out = Zeroizing::new(String::with_capacity(template.len()));
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start..].find("}}") else {
+            // Writing a reference out unfilled would look like success
+            if rest[start..].contains(SCHEME) {
+                let offset = template.len() - rest.len() + start;
+                let line = template[..offset].matches('\n').count() + 1;
+                return Err(CandadoError::InvalidInput(format!(
+                    "Unterminated reference on line {line} -> Close it with '}}}}'."
+                ))
+                .into());
+            }
+            break;
+        };
+        let placeholder = &rest[start..start + len + 2];
+        let offset = template.len() - rest.len() + start;
+        let line = template[..offset].matches('\n').count() + 1;
+        let invalid = || {
+            CandadoError::InvalidInput(format!(
+                "Invalid reference '{placeholder}' on line {line} -> Use {{{{ {SCHEME}<id>/<field> }}}}."
+            ))
+        };
+        out.push_str(&rest[..start]);
+        match placeholder[2..len].trim().strip_prefix(SCHEME) {
+            Some(reference) => {
+                let (id, field) = reference
+                    .split_once('/')
+                    .filter(|(id, field)| !id.is_empty() && !field.is_empty())
+                    .ok_or_else(invalid)?;
+                let value = resolve(id, field).with_context(|| {
+                    format!("Can't resolve '{SCHEME}{reference}' on line {line}")
+                })?;
+                out.push_str(value.expose());
+            }
+            // A reference inside a nested or unbalanced placeholder
+            None if placeholder.contains(SCHEME) => return Err(invalid().into()),
+            None => out.push_str(placeholder),
+        }
+        rest = &rest[start + len + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Writes `contents` to `path`, readable by the owner alone. An existing file
+/// is truncated and its permissions tightened before anything is written.
+pub fn write_private(path: &Path, contents: &str) -> Result<()> {
+    let write = || -> std::io::Result<()> {
+        let mut file = File::options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)?;
+        file.set_permissions(Permissions::from_mode(0o600))?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()
+    };
+    write().with_context(|| format!("Can't write '{}'", path.display()))
+}
+
+/// Reads the template at `path`.
+pub fn read(path: &Path) -> Result<Zeroizing<String>> {
+    fs::read_to_string(path)
+        .map(Zeroizing::new)
+        .with_context(|| format!("Can't read template '{}'", path.display()))
+}
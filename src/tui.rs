@@ -193,12 +193,10 @@ impl TableApp {
             item.into_iter()
                 .enumerate()
                 .map(|(pos, content)| {
-                    if pos == 3
-                        && !(self.unlocked && self.state.selected().or(Some(0)).unwrap() == i)
-                    {
-                        return Cell::from(Text::from(format!("{}", "*".repeat(content.len()))));
+                    if pos == 3 && !(self.unlocked && self.state.selected().unwrap_or(0) == i) {
+                        return Cell::from(Text::from("*".repeat(content.len())));
                     }
-                    Cell::from(Text::from(format!("{content}")))
+                    Cell::from(Text::from(content))
                 })
                 .collect::<Row>()
                 .style(Style::new().fg(self.colors.row_fg))
@@ -0,0 +1,298 @@
+use anyhow::{anyhow, Result};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use zeroize::Zeroizing;
+
+use crate::{
+    restore_process, template, CandadoError, CipherSuite, Credentials, Encrypter, Entry,
+    RecoveryKey, SecretString, SshKeyType, Storage, VaultDir, VerifyReport,
+};
+
+/// An unlocked vault and one open connection to its database.
+///
+/// Unlock once and run as many operations as needed, the key and the
+/// connection are kept until the vault is dropped.
+///
+/// # Basic usage:
+///
+/// ```no_run
+/// use candadolib::{Credentials, Entry, MasterSource, SecretString, Vault, VaultDir};
+///
+/// # fn main() -> anyhow::Result<()> {
+/// let mut credentials = Credentials::new(VaultDir::resolve(None)?, MasterSource::Prompt, None);
+/// let vault = Vault::unlock(&mut credentials)?;
+/// let password = Some(SecretString::from(Vault::password(24)));
+/// vault.add(Entry::new("github".into(), "me@example.com".into(), password, None, None))?;
+/// let entries = vault.find("github")?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Vault {
+    storage: Storage,
+}
+
+impl Vault {
+    /// Opens the database of the vault `encrypter` unlocks.
+    pub fn open(encrypter: Encrypter) -> Result<Self> {
+        Ok(Self {
+            storage: Storage::init(encrypter)?,
+        })
+    }
+
+    /// Unlocks the vault of `credentials`, through the agent if one is
+    /// running, and opens its database.
+    pub fn unlock(credentials: &mut Credentials) -> Result<Self> {
+        Vault::open(crate::unlock(credentials)?)
+    }
+
+    /// Unlocks the vault of `credentials` with its master, bypassing a running
+    /// agent, and opens its database.
+    pub fn unlock_master(credentials: &mut Credentials) -> Result<Self> {
+        Vault::open(crate::unlock_master(credentials)?)
+    }
+
+    pub fn encrypter(&self) -> &Encrypter {
+        self.storage.encrypter()
+    }
+
+    pub fn location(&self) -> &VaultDir {
+        self.encrypter().vault()
+    }
+
+    pub fn list(&self) -> Result<Vec<Entry>> {
+        self.storage.list()
+    }
+
+    pub fn read(&self, id: &str) -> Result<Entry> {
+        self.storage.read(id)
+    }
+
+    pub fn find(&self, query: &str) -> Result<Vec<Entry>> {
+        self.storage.find(query)
+    }
+
+    /// The one entry `query` stands for: the entry with that id, else the
+    /// entry whose service is `query` ignoring case, else the only entry whose
+    /// service or username contains `query` ignoring case.
+    ///
+    /// Secret fields are never matched, so a query can't tell whether some
+    /// entry has a password containing it.
+    pub fn resolve(&self, query: &str) -> Result<Entry> {
+        match self.storage.read(query) {
+            Err(e) if matches!(CandadoError::find(&e), Some(CandadoError::NotFound(_))) => {}
+            result => return result,
+        }
+
+        let entries = self.storage.list()?;
+        let (exact, rest): (Vec<Entry>, Vec<Entry>) = entries
+            .into_iter()
+            .partition(|entry| entry.service.eq_ignore_ascii_case(query));
+        let mut matches = match exact.is_empty() {
+            true => {
+                let query = query.to_lowercase();
+                rest.into_iter()
+                    .filter(|entry| {
+                        entry.service.to_lowercase().contains(&query)
+                            || entry.username.to_lowercase().contains(&query)
+                    })
+                    .collect()
+            }
+            false => exact,
+        };
+        match matches.len() {
+            0 => Err(CandadoError::NotFound(query.to_owned()).into()),
+            1 => Ok(matches.remove(0)),
+            count => Err(CandadoError::Ambiguous {
+                query: query.to_owned(),
+                count,
+            }
+            .into()),
+        }
+    }
+
+    /// The value of `field` of the entry `query` resolves to, see
+    /// [`Vault::resolve`] and [`Entry::field`].
+    pub fn get(&self, query: &str, field: &str) -> Result<SecretString> {
+        value(&self.resolve(query)?, field)
+    }
+
+    /// The value of `field` of entry `id`, see [`Entry::field`].
+    pub fn field(&self, id: &str, field: &str) -> Result<SecretString> {
+        value(&self.storage.read(id)?, field)
+    }
+
+    pub fn add(&self, entry: Entry) -> Result<()> {
+        self.storage.write(entry)
+    }
+
+    /// Overwrites the given fields of entry `id`, the others are kept. Custom
+    /// fields set to an empty value are removed.
+    #[allow(clippy::too_many_arguments)]
+    pub fn update(
+        &self,
+        id: &str,
+        service: Option<String>,
+        email: Option<String>,
+        password: Option<SecretString>,
+        username: Option<String>,
+        url: Option<String>,
+        custom: &[(String, SecretString)],
+    ) -> Result<()> {
+        let mut entry = self.storage.read(id)?;
+        entry.overite(service, email, password, username, url);
+        for (name, value) in custom {
+            entry.set_custom(name, value.expose())?;
+        }
+        self.storage.update(entry)
+    }
+
+    pub fn remove(&self, id: &str) -> Result<()> {
+        self.storage.remove(id)
+    }
+
+    /// Checks the whole vault for damaged entries, duplicate ids and keyfile
+    /// problems.
+    pub fn check(&self) -> Result<VerifyReport> {
+        self.storage.verify()
+    }
+
+    pub fn import(&mut self, file: PathBuf) -> Result<()> {
+        self.storage.import(file)
+    }
+
+    pub fn export(&self, file: PathBuf) -> Result<()> {
+        self.storage.export(file)
+    }
+
+    /// Writes the template `input` to `output` with every
+    /// `{{ candado://<id>/<field> }}` replaced by the field of that entry.
+    /// Nothing is written if a reference can't be resolved, and `output` is
+    /// only readable by the owner.
+    pub fn inject(&self, input: &Path, output: &Path) -> Result<()> {
+        let template = template::read(input)?;
+        let rendered = template::inject(&template, |id, field| self.field(id, field))?;
+        template::write_private(output, &rendered)
+    }
+
+    /// Builds `command` with every variable of `env` set to its field. The
+    /// program starts with the core dump limit candado started with, not the
+    /// one [`crate::harden_process`] set.
+    pub fn command(&self, env: &[EnvVar], command: &[String]) -> Result<Command> {
+        let (program, args) = command
+            .split_first()
+            .ok_or_else(|| CandadoError::InvalidInput("No command to run.".to_owned()))?;
+        let mut child = Command::new(program);
+        child.args(args);
+        for var in env {
+            child.env(&var.name, self.field(&var.id, &var.field)?.expose());
+        }
+        // SAFETY: restore_process only makes async-signal-safe calls.
+        unsafe {
+            child.pre_exec(|| {
+                restore_process();
+                Ok(())
+            });
+        }
+        Ok(child)
+    }
+
+    /// Re-encrypts every entry with a new data key, switching to `suite` if
+    /// given. Returns the new recovery key if the vault had one.
+    ///
+    /// The old recovery key can't unwrap the new data key, so a new one is
+    /// only added once the rotation is complete. A rotation that stops midway
+    /// and is finished by the next unlock leaves the vault without a recovery
+    /// key, instead of with one nobody has seen.
+    pub fn rotate_key(&mut self, suite: Option<CipherSuite>) -> Result<Option<RecoveryKey>> {
+        let encrypter = self.encrypter();
+        let had_recovery = encrypter.has_recovery();
+        let rotated = encrypter.rotate(suite.unwrap_or(encrypter.suite()))?;
+        self.storage.rotate(rotated)?;
+        match had_recovery {
+            true => self.recovery_key().map(Some),
+            false => Ok(None),
+        }
+    }
+
+    /// Generates a new recovery key, replacing the current one.
+    pub fn recovery_key(&mut self) -> Result<RecoveryKey> {
+        let encrypter = self.storage.encrypter_mut();
+        let recovery_key = encrypter.add_recovery()?;
+        encrypter.write_keyfile()?;
+        Ok(recovery_key)
+    }
+
+    /// Splits a new recovery secret into `shares`, any `threshold` of them
+    /// set a new master. Replaces the current shares.
+    pub fn split(&mut self, shares: u8, threshold: u8) -> Result<Vec<SecretString>> {
+        let encrypter = self.storage.encrypter_mut();
+        let secret = encrypter.add_shares()?;
+        let shares = secret.split(shares, threshold)?;
+        encrypter.write_keyfile()?;
+        Ok(shares)
+    }
+
+    //------------------------------------------
+    // Generators
+    //------------------------------------------
+
+    pub fn password(length: u32) -> String {
+        crate::password(length)
+    }
+
+    pub fn token(length: u32) -> String {
+        crate::token(length)
+    }
+
+    pub fn key(length: u32) -> String {
+        crate::key(length)
+    }
+
+    pub fn passphrase(length: u32, wordlist: &Option<PathBuf>) -> String {
+        crate::passphrase(length, wordlist)
+    }
+
+    pub fn ssh_key(key_type: SshKeyType, comment: &str) -> Result<(Zeroizing<String>, String)> {
+        crate::ssh_key(key_type, comment)
+    }
+}
+
+/// A `VAR=ID:FIELD` argument, setting an environment variable to a field of
+/// an entry. The field defaults to the password.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvVar {
+    pub name: String,
+    pub id: String,
+    pub field: String,
+}
+
+impl FromStr for EnvVar {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, reference) = s
+            .split_once('=')
+            .filter(|(name, _)| !name.is_empty())
+            .ok_or_else(|| anyhow!("expected VAR=ID:FIELD, got '{s}'"))?;
+        let (id, field) = reference.split_once(':').unwrap_or((reference, "password"));
+        Ok(Self {
+            name: name.to_owned(),
+            id: id.to_owned(),
+            field: field.to_owned(),
+        })
+    }
+}
+
+/// The value of `field` of `entry`, [`CandadoError::MissingField`] if it has none.
+fn value(entry: &Entry, field: &str) -> Result<SecretString> {
+    match entry.field(field) {
+        Some(value) => Ok(SecretString::from(value)),
+        None => Err(CandadoError::MissingField {
+            id: entry.id.clone(),
+            field: field.to_owned(),
+        }
+        .into()),
+    }
+}
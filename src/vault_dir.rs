@@ -0,0 +1,194 @@
+use anyhow::{anyhow, Result};
+use core::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::agent::SOCKET_NAME;
+use crate::keyfile::{Keyfile, KEYFILE_NAME};
+use crate::ssh_agent::SSH_SOCKET_NAME;
+use crate::PROGRAM_FOLDER;
+
+/// Environment variable overriding the folder that holds the default vault
+/// and the named vaults, `$HOME/.candado` if unset.
+pub const HOME_ENV: &str = "CANDADO_HOME";
+
+/// Name of the vault that lives directly in the candado home.
+pub const DEFAULT_VAULT: &str = "default";
+
+pub const DATABASE_NAME: &str = "candado.db";
+
+/// File locked while the keyfile is replaced or the data key rotated.
+pub const LOCK_NAME: &str = ".candado.lock";
+
+/// Folder below the candado home holding one folder per named vault.
+const VAULTS_FOLDER: &str = "vaults";
+
+/// Exclusive lock on a vault, released when dropped.
+///
+/// Held across every read-modify-write of the keyfile and across a whole key
+/// rotation, so two processes never replace the keyfile based on different
+/// versions of it.
+pub struct VaultLock {
+    _file: File,
+}
+
+/// The folder of one vault, holding its keyfile, database and agent socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VaultDir {
+    path: PathBuf,
+}
+
+impl VaultDir {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// The folder of the default vault, [`HOME_ENV`] or `$HOME/.candado`.
+    pub fn home() -> Result<PathBuf> {
+        if let Some(home) = std::env::var_os(HOME_ENV).filter(|home| !home.is_empty()) {
+            return Ok(PathBuf::from(home));
+        }
+
+        // Linux
+        #[cfg(target_os = "linux")]
+        let home = format!("{}/{}", std::env::var("HOME")?, PROGRAM_FOLDER);
+
+        // NOTE: Support for Mac os and Windows will be added in the future
+        // MacOs
+        // #[cfg(target_os = "macos")]
+        // let home = format!("{}/.candado", std::env::var("HOME")?);
+        // windows
+        // #[cfg(target_os = "windows")]
+        // let home = format!("{}/.candado", std::env::var("USERHOME")?);
+
+        Ok(PathBuf::from(home))
+    }
+
+    /// Picks the vault given to `--vault`, the default vault if `None`.
+    ///
+    /// Anything containing a `/` is taken as the path of a vault folder,
+    /// anything else as the name of a vault in the candado home.
+    pub fn resolve(vault: Option<&str>) -> Result<Self> {
+        match vault {
+            None => Ok(VaultDir::new(VaultDir::home()?)),
+            Some(path) if path.contains('/') => Ok(VaultDir::new(path)),
+            Some(name) => VaultDir::named(name),
+        }
+    }
+
+    pub fn named(name: &str) -> Result<Self> {
+        if name == DEFAULT_VAULT {
+            return Ok(VaultDir::new(VaultDir::home()?));
+        }
+        let valid = !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !valid {
+            return Err(anyhow!(
+                "Invalid vault name '{name}' -> Use letters, digits, '-', '_' and '.', or a path containing '/'."
+            ));
+        }
+        Ok(VaultDir::new(
+            VaultDir::home()?.join(VAULTS_FOLDER).join(name),
+        ))
+    }
+
+    /// Every initialized vault in the candado home, the default one first and
+    /// the named ones sorted by name.
+    pub fn list() -> Result<Vec<(String, VaultDir)>> {
+        let home = VaultDir::home()?;
+        let mut vaults = vec![];
+        let default = VaultDir::new(&home);
+        if default.exists() {
+            vaults.push((DEFAULT_VAULT.to_owned(), default));
+        }
+
+        let mut named = vec![];
+        if let Ok(dir) = fs::read_dir(home.join(VAULTS_FOLDER)) {
+            for entry in dir {
+                let entry = entry?;
+                let vault = VaultDir::new(entry.path());
+                if vault.exists() {
+                    named.push((entry.file_name().to_string_lossy().to_string(), vault));
+                }
+            }
+        }
+        named.sort_by(|a, b| a.0.cmp(&b.0));
+        vaults.extend(named);
+        Ok(vaults)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn keyfile(&self) -> PathBuf {
+        self.path.join(KEYFILE_NAME)
+    }
+
+    pub fn database(&self) -> PathBuf {
+        self.path.join(DATABASE_NAME)
+    }
+
+    pub fn socket(&self) -> PathBuf {
+        self.path.join(SOCKET_NAME)
+    }
+
+    pub fn ssh_socket(&self) -> PathBuf {
+        self.path.join(SSH_SOCKET_NAME)
+    }
+
+    /// Waits until no other process holds the lock of this vault and takes it.
+    ///
+    /// The lock is per open file, so a process that already holds it blocks
+    /// as well when locking again.
+    pub fn lock(&self) -> Result<VaultLock> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(self.path.join(LOCK_NAME))?;
+        loop {
+            // SAFETY: flock only uses the descriptor, which `file` keeps open.
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+                return Ok(VaultLock { _file: file });
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err.into());
+            }
+        }
+    }
+
+    /// Whether a vault was initialized in this folder.
+    pub fn exists(&self) -> bool {
+        self.keyfile().exists()
+    }
+
+    /// Deletes the keyfile and database of this vault. The folder itself is
+    /// kept, the default vault shares it with the named ones.
+    pub fn remove(&self) -> Result<()> {
+        let staged = [Keyfile::staged_path(&self.keyfile())]
+            .into_iter()
+            .chain(Keyfile::rotations(&self.keyfile())?);
+        for path in [self.keyfile(), self.database()].into_iter().chain(staged) {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for VaultDir {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path.display())
+    }
+}